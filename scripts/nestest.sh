@@ -0,0 +1,22 @@
+#!/bin/sh
+# Downloads nestest.nes and nestest.log into tests/nestest/ and runs the
+# ignored CPU conformance test against them.
+#
+#   scripts/nestest.sh [extra cargo test args]
+#
+# NESTEST_URL can point to another mirror of the two files.
+set -eu
+
+NESTEST_URL=${NESTEST_URL:-https://www.qmtpro.com/~nes/misc}
+
+cd "$(dirname "$0")/.."
+mkdir -p tests/nestest
+for file in nestest.nes nestest.log; do
+    if [ ! -f "tests/nestest/$file" ]; then
+        echo "downloading $file"
+        curl -fsSL -o "tests/nestest/$file.part" "$NESTEST_URL/$file"
+        mv "tests/nestest/$file.part" "tests/nestest/$file"
+    fi
+done
+
+cargo test --test nestest "$@" -- --ignored
@@ -0,0 +1,117 @@
+// dump PRG ROM of a ROM as 6502 assembly
+//
+// usage: rust-nes-disasm <rom> [--symbols FILE] [--bank N]
+//
+// PRG ROM is split into 16KB banks. switchable banks are shown at $8000, the last bank at $C000.
+// symbols file has one "ADDR NAME" per line(e.g. "C000 reset"), names replace the addresses.
+extern crate rust_nes;
+
+use rust_nes::disasm::{self, Symbols};
+use rust_nes::Rom;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::exit;
+
+const BANK_SIZE: usize = 0x4000;
+
+struct Options {
+    rom_filename: String,
+    symbols_filename: Option<String>,
+    bank: Option<usize>,
+}
+
+fn usage() -> String {
+    "usage: rust-nes-disasm <rom> [--symbols FILE] [--bank N]".to_owned()
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom_filename: String::new(),
+        symbols_filename: None,
+        bank: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--symbols" => options.symbols_filename = Some(value()?),
+            "--bank" => {
+                options.bank = Some(value()?.parse().map_err(|_| "--bank needs a number".to_owned())?)
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option:{}", arg)),
+            _ if options.rom_filename.is_empty() => options.rom_filename = arg.clone(),
+            _ => return Err(usage()),
+        }
+    }
+    if options.rom_filename.is_empty() {
+        return Err(usage());
+    }
+    Ok(options)
+}
+
+// CPU address where the bank is shown
+fn bank_base(bank: usize, banks: usize) -> u16 {
+    if bank == banks - 1 {
+        0xC000
+    } else {
+        0x8000
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let rom = Rom::load(&options.rom_filename).map_err(|err| err.to_string())?;
+    let symbols = match options.symbols_filename {
+        Some(ref filename) => {
+            let mut text = String::new();
+            File::open(filename)
+                .and_then(|mut file| file.read_to_string(&mut text))
+                .map_err(|err| format!("{}: {}", filename, err))?;
+            Some(Symbols::parse(&text).map_err(|err| format!("{}: {}", filename, err))?)
+        }
+        None => None,
+    };
+
+    let banks: Vec<&[u8]> = rom.prg().chunks(BANK_SIZE).collect();
+    if let Some(bank) = options.bank {
+        if bank >= banks.len() {
+            return Err(format!("bank {} is out of range, ROM has {} banks", bank, banks.len()));
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (bank, data) in banks.iter().enumerate() {
+        if options.bank.is_some_and(|only| only != bank) {
+            continue;
+        }
+        let base = bank_base(bank, banks.len());
+        writeln!(out, "; bank {} (${:04X})", bank, base).map_err(|err| err.to_string())?;
+        for line in disasm::disassemble(data, base, symbols.as_ref()) {
+            writeln!(out, "{}", line).map_err(|err| err.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = parse_options(&args).and_then(|options| run(&options));
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_bank_is_at_c000() {
+        assert_eq!(bank_base(0, 1), 0xC000);
+        assert_eq!(bank_base(0, 8), 0x8000);
+        assert_eq!(bank_base(7, 8), 0xC000);
+    }
+}
@@ -0,0 +1,250 @@
+// run a ROM without display, for CI and batch testing
+//
+// usage: rust-nes-headless <rom> [--frames N] [--until ADDR=VALUE] [--input FILE]
+//                                [--screenshot FILE] [--dump-ram FILE] [--trace FILE]
+//
+// input file has one line per change of buttons: "<frame> <buttons>",
+// buttons are joined by '+' (A, B, SELECT, START, UP, DOWN, LEFT, RIGHT), or "-" to release all.
+// e.g.
+//   # press start for 5 frames
+//   60 START
+//   65 -
+extern crate bmp;
+extern crate rust_nes;
+
+use rust_nes::joypad;
+use rust_nes::Nes;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::process::exit;
+
+const DEFAULT_FRAMES: u64 = 60;
+
+struct Options {
+    rom_filename: String,
+    frames: u64,
+    until: Option<(u16, u8)>,
+    input_filename: Option<String>,
+    screenshot_filename: Option<String>,
+    dump_ram_filename: Option<String>,
+    trace_filename: Option<String>,
+}
+
+fn usage() -> String {
+    "usage: rust-nes-headless <rom> [--frames N] [--until ADDR=VALUE] [--input FILE] \
+     [--screenshot FILE] [--dump-ram FILE] [--trace FILE]"
+        .to_owned()
+}
+
+// "6000", "0x6000" or "$6000"
+fn hex_digits(value: &str) -> &str {
+    value.trim_start_matches("0x").trim_start_matches('$')
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom_filename: String::new(),
+        frames: DEFAULT_FRAMES,
+        until: None,
+        input_filename: None,
+        screenshot_filename: None,
+        dump_ram_filename: None,
+        trace_filename: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--frames" => {
+                options.frames = value()?
+                    .parse()
+                    .map_err(|_| "--frames needs a number".to_owned())?
+            }
+            "--until" => {
+                let condition = value()?;
+                let mut parts = condition.splitn(2, '=');
+                let addr = parts.next().unwrap();
+                let data = parts.next().ok_or_else(|| "--until needs ADDR=VALUE".to_owned())?;
+                let addr = u16::from_str_radix(hex_digits(addr), 16)
+                    .map_err(|_| format!("invalid address(0000-FFFF):{}", addr))?;
+                let data = u8::from_str_radix(hex_digits(data), 16)
+                    .map_err(|_| format!("invalid value(00-FF):{}", data))?;
+                options.until = Some((addr, data));
+            }
+            "--input" => options.input_filename = Some(value()?),
+            "--screenshot" => options.screenshot_filename = Some(value()?),
+            "--dump-ram" => options.dump_ram_filename = Some(value()?),
+            "--trace" => options.trace_filename = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option:{}", arg)),
+            _ if options.rom_filename.is_empty() => options.rom_filename = arg.clone(),
+            _ => return Err(usage()),
+        }
+    }
+    if options.rom_filename.is_empty() {
+        return Err(usage());
+    }
+    Ok(options)
+}
+
+fn parse_buttons(buttons: &str) -> Result<u8, String> {
+    if buttons == "-" {
+        return Ok(0);
+    }
+    let mut state = 0u8;
+    for button in buttons.split('+') {
+        state |= match button.to_uppercase().as_str() {
+            "A" => joypad::BUTTON_A,
+            "B" => joypad::BUTTON_B,
+            "SELECT" => joypad::BUTTON_SELECT,
+            "START" => joypad::BUTTON_START,
+            "UP" => joypad::BUTTON_UP,
+            "DOWN" => joypad::BUTTON_DOWN,
+            "LEFT" => joypad::BUTTON_LEFT,
+            "RIGHT" => joypad::BUTTON_RIGHT,
+            _ => return Err(format!("unknown button:{}", button)),
+        };
+    }
+    Ok(state)
+}
+
+// (frame, button state) sorted by frame
+fn parse_input_script(script: &str) -> Result<Vec<(u64, u8)>, String> {
+    let mut inputs = vec![];
+    for (no, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let frame = fields
+            .next()
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("line {}: invalid frame", no + 1))?;
+        let buttons = fields.next().unwrap_or("-");
+        let state = parse_buttons(buttons).map_err(|err| format!("line {}: {}", no + 1, err))?;
+        inputs.push((frame, state));
+    }
+    inputs.sort_by_key(|&(frame, _)| frame);
+    Ok(inputs)
+}
+
+fn save_screenshot(nes: &Nes, filename: &str) -> Result<(), String> {
+    let (width, height) = nes.screen_size();
+    let mut img = vec![0u8; (width * height * 4) as usize];
+    nes.render_image(&mut img);
+
+    let mut image = bmp::Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let index = ((x + y * width) * 4) as usize;
+            // B, G, R
+            let pixel = bmp::Pixel::new(img[index + 2], img[index + 1], img[index]);
+            image.set_pixel(x, y, pixel);
+        }
+    }
+    image.save(filename).map_err(|err| err.to_string())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let inputs = match options.input_filename {
+        Some(ref filename) => {
+            let mut script = String::new();
+            File::open(filename)
+                .and_then(|mut file| file.read_to_string(&mut script))
+                .map_err(|err| format!("{}: {}", filename, err))?;
+            parse_input_script(&script)?
+        }
+        None => vec![],
+    };
+
+    let mut nes = Nes::new();
+    nes.load_rom(&options.rom_filename).map_err(|err| err.to_string())?;
+
+    let mut trace = match options.trace_filename {
+        Some(ref filename) => {
+            nes.set_trace(true);
+            let file = File::create(filename).map_err(|err| format!("{}: {}", filename, err))?;
+            Some(BufWriter::new(file))
+        }
+        None => None,
+    };
+    let mut trace_lines = vec![];
+
+    let mut inputs = inputs.iter().peekable();
+    let mut frame = 0;
+    while frame < options.frames {
+        while let Some(&&(input_frame, state)) = inputs.peek() {
+            if input_frame > frame {
+                break;
+            }
+            nes.set_joypad_button_state(state);
+            inputs.next();
+        }
+
+        nes.step_frame();
+        frame += 1;
+
+        if let Some(ref mut file) = trace {
+            nes.take_trace(&mut trace_lines);
+            for line in trace_lines.drain(..) {
+                writeln!(file, "{}", line).map_err(|err| err.to_string())?;
+            }
+        }
+
+        if let Some((addr, data)) = options.until {
+            if nes.peek(addr) == Some(data) {
+                break;
+            }
+        }
+    }
+    println!("frames:{}", frame);
+
+    if let Some(ref filename) = options.screenshot_filename {
+        save_screenshot(&nes, filename)?;
+    }
+    if let Some(ref filename) = options.dump_ram_filename {
+        File::create(filename)
+            .and_then(|mut file| file.write_all(&nes.ram()))
+            .map_err(|err| format!("{}: {}", filename, err))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = parse_options(&args).and_then(|options| run(&options));
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_script_is_sorted_by_frame() {
+        let script = "# comment\n65 -\n60 START+a\n\n";
+        let inputs = parse_input_script(script).unwrap();
+        assert_eq!(inputs, vec![(60, joypad::BUTTON_START | joypad::BUTTON_A), (65, 0)]);
+        assert!(parse_input_script("10 JUMP").is_err());
+    }
+
+    #[test]
+    fn until_condition_is_hex() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+        let options = parse_options(&args(&["rom.nes", "--until", "6000=80", "--frames", "10"])).unwrap();
+        assert_eq!(options.until, Some((0x6000, 0x80)));
+        assert_eq!(options.frames, 10);
+        let options = parse_options(&args(&["rom.nes", "--until", "$6000=0x80"])).unwrap();
+        assert_eq!(options.until, Some((0x6000, 0x80)));
+        // out of range, not truncated
+        assert!(parse_options(&args(&["rom.nes", "--until", "16000=80"])).is_err());
+        assert!(parse_options(&args(&["rom.nes", "--until", "6000=180"])).is_err());
+        assert!(parse_options(&args(&["rom.nes", "--until", "6000"])).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+// NES emulator core. frontends(SDL window, headless runner) are built on this crate.
+// the code is written in Rust 2015 style: `...` range patterns and `field: field` initializers
+#![allow(ellipsis_inclusive_range_patterns)]
+#![allow(clippy::redundant_field_names)]
+extern crate bmp;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate bitflags;
+
+pub mod nes;
+
+pub use nes::{Mirroring, Nes, StateError};
+pub use nes::disasm;
+pub use nes::joypad;
+pub use nes::rewind::Rewind;
+pub use nes::rom::{ConsoleType, Rom, RomError, RomInfo, Timing};
@@ -1,40 +1,43 @@
-mod nes;
-
-extern crate bmp;
 extern crate env_logger;
 #[macro_use]
 extern crate log;
+extern crate rust_nes;
 extern crate sdl2;
-#[macro_use]
-extern crate bitflags;
 
-use bmp::Image;
-use nes::Nes;
-use nes::joypad;
-use nes::rom::Rom;
+use rust_nes::joypad;
+use rust_nes::{Nes, Rewind, Rom};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Scancode, LSHIFTMOD, RSHIFTMOD};
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::render::Texture;
 use sdl2::video::Window;
 use std::collections::HashSet;
 use std::env;
-use std::io::{self, Write};
-use std::process::exit;
-use std::rc::Rc;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use std::{thread, time};
 
-fn get_rom_filename() -> Result<(String), (String)> {
+const AUDIO_SAMPLE_RATE: i32 = 44_100;
+const AUDIO_CHUNK_SAMPLES: usize = 512;
+// drop samples instead of growing latency when emulation runs ahead of playback
+const MAX_QUEUED_AUDIO_BYTES: u32 = (AUDIO_SAMPLE_RATE as u32) * 4 / 5; // 200ms of f32
+// snapshot every frame, 10 seconds
+const REWIND_DEPTH: usize = 600;
+const REWIND_INTERVAL: u32 = 1;
+// battery RAM is also written while running, so a crash loses at most this much
+const BATTERY_SAVE_INTERVAL_SECS: u64 = 5;
+
+fn get_rom_filename() -> Result<String, String> {
     if env::args().count() != 2 {
         return Err("need only one argument".to_owned());
     }
     Ok(env::args().nth(1).unwrap())
 }
 
-fn run_nes() -> Result<(), (String)> {
+fn run_nes() -> Result<(), String> {
     env_logger::init();
 
     if env::args().count() != 2 {
@@ -67,13 +70,26 @@ fn run_nes() -> Result<(), (String)> {
 
     let creator = canvas.texture_creator();
 
+    // audio
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    audio_queue.resume();
+
     // event for input device
     let mut events = sdl_context.event_pump().unwrap();
 
-    let mut nes = Nes::new();
-    let rom = Rom::load(rom_filename).unwrap();
+    let rom = Rom::load(&rom_filename).map_err(|err| err.to_string())?;
     rom.print();
     nes.set_rom(rom.clone());
+    let save_filename = Path::new(&rom_filename).with_extension("sav");
+    nes.load_battery_ram(&save_filename)
+        .map_err(|err| format!("{}: {}", save_filename.display(), err))?;
+    nes.set_audio_sample_rate(audio_queue.spec().freq as u32);
     nes.reset();
 
     let mut texture = creator
@@ -83,9 +99,14 @@ fn run_nes() -> Result<(), (String)> {
     let mut slow = false;
     let mut prev_render_time = SystemTime::now();
     let mut prev_poll_event_time = SystemTime::now();
+    let mut prev_battery_save_time = SystemTime::now();
     let mut button_state = 0u8;
     let mut button_state_changed = false;
+    // created after the ROM is loaded, so it never holds states of another ROM
+    let mut rewind = Rewind::new(REWIND_DEPTH, REWIND_INTERVAL);
+    let mut rewinding = false;
     let mut img = vec![0u8; (screen_width * screen_height * 4) as usize]; // RGBA
+    let mut audio_samples = Vec::with_capacity(AUDIO_CHUNK_SAMPLES * 2);
 
     'running: loop {
         let elapsed = prev_poll_event_time.elapsed().unwrap();
@@ -105,6 +126,28 @@ fn run_nes() -> Result<(), (String)> {
                     } => {
                         slow = !slow;
                     }
+                    // F1-F10: load state slot, Shift+F1-F10: save
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } if state_slot(keycode).is_some() => {
+                        let slot = state_slot(keycode).unwrap();
+                        let filename = state_filename(&rom_filename, slot);
+                        let result = if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                            fs::write(&filename, nes.save_state()).map_err(|err| err.to_string())
+                        } else {
+                            fs::read(&filename)
+                                .map_err(|err| err.to_string())
+                                .and_then(|data| nes.load_state(&data).map_err(|err| err.to_string()))
+                                // older frames would rewind across the jump
+                                .map(|_| rewind.clear())
+                        };
+                        if let Err(err) = result {
+                            eprintln!("state slot {}: {}", slot, err);
+                        }
+                    }
                     Event::KeyDown { .. } | Event::KeyUp { .. } => {
                         button_state_changed = true;
                     }
@@ -117,12 +160,20 @@ fn run_nes() -> Result<(), (String)> {
 
         if button_state_changed {
             button_state = get_button_state(&events);
+            rewinding = is_rewind_pressed(&events);
             button_state_changed = false;
         }
 
         nes.set_joypad_button_state(button_state);
         nes.tick();
 
+        if rewinding {
+            nes.take_audio_samples(&mut audio_samples);
+            audio_samples.clear();
+        } else {
+            queue_audio(&nes, &audio_queue, &mut audio_samples);
+        }
+
         if slow {
             thread::sleep(time::Duration::from_millis(100));
         }
@@ -132,12 +183,29 @@ fn run_nes() -> Result<(), (String)> {
         }
         nes.reset_screen_rendered();
 
+        // while held, go back one snapshot per frame, then the frame after it is drawn
+        if rewinding {
+            if let Some(state) = rewind.pop() {
+                nes.load_state(&state).unwrap();
+            }
+        } else {
+            rewind.push(&nes.save_state());
+        }
+
+        if prev_battery_save_time.elapsed().unwrap().as_secs() >= BATTERY_SAVE_INTERVAL_SECS {
+            if nes.is_battery_ram_dirty() {
+                if let Err(err) = nes.save_battery_ram(&save_filename) {
+                    eprintln!("{}: {}", save_filename.display(), err);
+                }
+            }
+            prev_battery_save_time = SystemTime::now();
+        }
+
         // TODO:
         let elapsed = prev_render_time.elapsed().unwrap();
         if elapsed.subsec_nanos() < 100_000_000 {  // every 100ms
             continue;
         }
-        prev_render_time = SystemTime::now();
 
         info!("========== draw image ===============");
         render_nes_screen(&nes, &mut img, &mut canvas, &mut texture);
@@ -147,9 +215,37 @@ fn run_nes() -> Result<(), (String)> {
         // dumping ram & ppu
         // nes.dump();
     }
+    nes.save_battery_ram(&save_filename)
+        .map_err(|err| format!("{}: {}", save_filename.display(), err))?;
     Ok(())
 }
 
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        Keycode::F5 => Some(5),
+        Keycode::F6 => Some(6),
+        Keycode::F7 => Some(7),
+        Keycode::F8 => Some(8),
+        Keycode::F9 => Some(9),
+        Keycode::F10 => Some(10),
+        _ => None,
+    }
+}
+
+// foo.nes -> foo.ss1
+fn state_filename(rom_filename: &str, slot: u8) -> PathBuf {
+    Path::new(rom_filename).with_extension(format!("ss{}", slot))
+}
+
+// held Backspace plays backwards
+fn is_rewind_pressed(events: &sdl2::EventPump) -> bool {
+    events.keyboard_state().is_scancode_pressed(Scancode::Backspace)
+}
+
 fn get_button_state(events: &sdl2::EventPump) -> u8 {
     let keys: HashSet<Keycode> = events
         .keyboard_state()
@@ -173,25 +269,36 @@ fn get_button_state(events: &sdl2::EventPump) -> u8 {
             }
         }
     }
-    return button_state;
+    button_state
+}
+
+fn queue_audio(nes: &Nes, audio_queue: &AudioQueue<f32>, samples: &mut Vec<f32>) {
+    nes.take_audio_samples(samples);
+    if samples.len() < AUDIO_CHUNK_SAMPLES {
+        return;
+    }
+    if audio_queue.size() < MAX_QUEUED_AUDIO_BYTES {
+        audio_queue.queue(samples);
+    }
+    samples.clear();
 }
 
 fn render_nes_screen(
     nes: &Nes,
-    img: &mut Vec<u8>,
+    img: &mut [u8],
     canvas: &mut Canvas<Window>,
     texture: &mut Texture,
 ) {
     nes.render_image(img);
 
     texture
-        .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+        .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
             buffer.copy_from_slice(img);
         })
         .unwrap();
 
     canvas
-        .copy(&texture, None, None)
+        .copy(texture, None, None)
         .unwrap();
 
     canvas.present();
@@ -201,7 +308,7 @@ fn main() {
     ::std::process::exit(match run_nes() {
         Ok(_) => 0,
         Err(err) => {
-            writeln!(io::stderr(), "error: {:?}", err).unwrap();
+            eprintln!("error: {:?}", err);
             1
         }
     });
@@ -0,0 +1,276 @@
+use nes::state::{StateError, StateReader, StateWriter};
+
+// timer period(CPU cycles, NTSC)
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub struct Dmc {
+    is_irq_enabled: bool,
+    is_loop: bool,
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+
+    sample_address: u16, // $4012
+    sample_length: u16,  // $4013
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+    is_silence: bool,
+
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            is_irq_enabled: false,
+            is_loop: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+
+            shift_register: 0,
+            bits_remaining: 8,
+            is_silence: true,
+
+            irq: false,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                // IL-- RRRR
+                self.is_irq_enabled = (data & 0x80) != 0;
+                self.is_loop = (data & 0x40) != 0;
+                self.timer_period = RATE_TABLE[(data & 0x0F) as usize];
+                if !self.is_irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => {
+                // -DDD DDDD
+                self.output_level = data & 0x7F;
+            }
+            2 => {
+                // $C000 + A * 64
+                self.sample_address = 0xC000 | ((data as u16) << 6);
+            }
+            3 => {
+                // L * 16 + 1
+                self.sample_length = ((data as u16) << 4) | 0x0001;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // address of the next sample byte, when the sample buffer is empty
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.is_loop {
+                self.restart();
+            } else if self.is_irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.is_silence {
+            if (self.shift_register & 0x01) != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.is_silence = false;
+                    self.shift_register = data;
+                }
+                None => {
+                    self.is_silence = true;
+                }
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_irq_enabled);
+        state.write_bool(self.is_loop);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.output_level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_bool(self.sample_buffer.is_some());
+        state.write_u8(self.sample_buffer.unwrap_or(0));
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.is_silence);
+        state.write_bool(self.irq);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_irq_enabled = state.read_bool()?;
+        self.is_loop = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        if self.timer_period == 0 {
+            return Err(StateError::InvalidValue("DMC timer period"));
+        }
+        self.timer = state.read_u16()?;
+        self.output_level = state.read_u8()?;
+        if self.output_level > 127 {
+            return Err(StateError::InvalidValue("DMC output level"));
+        }
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        let has_sample = state.read_bool()?;
+        let sample = state.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        if self.bits_remaining == 0 || self.bits_remaining > 8 {
+            return Err(StateError::InvalidValue("DMC bits remaining"));
+        }
+        self.is_silence = state.read_bool()?;
+        self.irq = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fetch one sample byte and let the output unit consume it
+    fn fetch(dmc: &mut Dmc) -> Option<u16> {
+        let addr = dmc.fetch_address();
+        if addr.is_some() {
+            dmc.fill_sample_buffer(0x00);
+            dmc.sample_buffer = None;
+        }
+        addr
+    }
+
+    #[test]
+    fn sample_address_wraps_to_8000() {
+        let mut dmc = Dmc::new();
+        dmc.write(2, 0xFF); // $FFC0
+        dmc.write(3, 0x04); // 65 bytes
+        dmc.set_enabled(true);
+
+        for i in 0..0x40 {
+            assert_eq!(fetch(&mut dmc), Some(0xFFC0 + i));
+        }
+        assert_eq!(fetch(&mut dmc), Some(0x8000));
+        assert_eq!(fetch(&mut dmc), None);
+    }
+
+    #[test]
+    fn sample_end_raises_irq_or_loops() {
+        let mut dmc = Dmc::new();
+        dmc.write(0, 0x80);
+        dmc.write(3, 0x00); // 1 byte
+        dmc.set_enabled(true);
+        fetch(&mut dmc);
+        assert!(dmc.irq);
+        assert!(!dmc.is_active());
+
+        dmc.write(0, 0x40); // loop, clears IRQ
+        assert!(!dmc.irq);
+        dmc.set_enabled(true);
+        fetch(&mut dmc);
+        assert!(dmc.is_active());
+        assert_eq!(fetch(&mut dmc), Some(0xC000));
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_values() {
+        let load = |dmc: &Dmc| {
+            let mut state = StateWriter::payload();
+            dmc.save_state(&mut state);
+            let data = state.into_bytes();
+            Dmc::new().load_state(&mut StateReader::payload(&data))
+        };
+        assert_eq!(load(&Dmc::new()), Ok(()));
+
+        let mut dmc = Dmc::new();
+        dmc.timer_period = 0;
+        assert_eq!(load(&dmc), Err(StateError::InvalidValue("DMC timer period")));
+
+        let mut dmc = Dmc::new();
+        dmc.output_level = 128;
+        assert_eq!(load(&dmc), Err(StateError::InvalidValue("DMC output level")));
+
+        let mut dmc = Dmc::new();
+        dmc.bits_remaining = 9;
+        assert_eq!(load(&dmc), Err(StateError::InvalidValue("DMC bits remaining")));
+    }
+}
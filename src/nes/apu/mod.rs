@@ -0,0 +1,550 @@
+mod dmc;
+mod noise;
+mod pulse;
+mod triangle;
+
+use nes::mbc::Mbc;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::rc::Weak;
+use self::dmc::Dmc;
+use self::noise::Noise;
+use self::pulse::Pulse;
+use self::triangle::Triangle;
+
+const CPU_CLOCK: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// frame counter sequence (CPU cycles)
+const FRAME_STEP1: u64 = 7457;
+const FRAME_STEP2: u64 = 14913;
+const FRAME_STEP3: u64 = 22371;
+const FRAME_STEP4: u64 = 29829;
+const FRAME_STEP5: u64 = 37281;
+
+// high pass filter cutoff(Hz), removes DC offset of the mixer output
+const HIGH_PASS_CUTOFF: f32 = 90.0;
+
+// volume envelope shared by pulse and noise
+struct Envelope {
+    start: bool,
+    is_loop: bool,
+    is_constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            start: false,
+            is_loop: false,
+            is_constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV
+    fn write(&mut self, data: u8) {
+        self.is_loop = (data & 0x20) != 0;
+        self.is_constant = (data & 0x10) != 0;
+        self.volume = data & 0x0F;
+    }
+
+    fn restart(&mut self) {
+        self.start = true;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.is_loop {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.is_constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.start);
+        state.write_bool(self.is_loop);
+        state.write_bool(self.is_constant);
+        state.write_u8(self.volume);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.read_bool()?;
+        self.is_loop = state.read_bool()?;
+        self.is_constant = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        self.decay = state.read_u8()?;
+        // 4 bit values, the output indexes the mixer tables
+        if self.volume > 15 {
+            return Err(StateError::InvalidValue("envelope volume"));
+        }
+        if self.decay > 15 {
+            return Err(StateError::InvalidValue("envelope decay"));
+        }
+        Ok(())
+    }
+}
+
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.halt);
+        state.write_u8(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.halt = state.read_bool()?;
+        self.counter = state.read_u8()?;
+        Ok(())
+    }
+}
+
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    // frame counter($4017)
+    is_five_step: bool,
+    is_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u64,
+
+    cycle: u64,
+
+    // mixer
+    pulse_table: Vec<f32>,
+    tnd_table: Vec<f32>,
+
+    // output samples
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    sample_timer: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    filter_alpha: f32,
+    filter_prev_input: f32,
+    filter_prev_output: f32,
+    samples: Vec<f32>,
+
+    mbc: Weak<RefCell<Box<Mbc>>>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        // https://wiki.nesdev.com/w/index.php/APU_Mixer (lookup table)
+        let mut pulse_table = vec![0.0f32; 31];
+        for (n, value) in pulse_table.iter_mut().enumerate().skip(1) {
+            *value = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd_table = vec![0.0f32; 203];
+        for (n, value) in tnd_table.iter_mut().enumerate().skip(1) {
+            *value = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        let mut apu = Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            is_five_step: false,
+            is_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+
+            cycle: 0,
+
+            pulse_table: pulse_table,
+            tnd_table: tnd_table,
+
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            sample_timer: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            filter_alpha: 0.0,
+            filter_prev_input: 0.0,
+            filter_prev_output: 0.0,
+            samples: vec![],
+
+            mbc: Weak::default(),
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    pub fn set_mbc(&mut self, mbc: Weak<RefCell<Box<Mbc>>>) {
+        self.mbc = mbc;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cycles_per_sample = CPU_CLOCK / sample_rate as f64;
+
+        let rc = 1.0 / (2.0 * ::std::f32::consts::PI * HIGH_PASS_CUTOFF);
+        let dt = 1.0 / sample_rate as f32;
+        self.filter_alpha = rc / (rc + dt);
+    }
+
+    // move generated samples(mono, -1.0..1.0) to buffer
+    pub fn take_samples(&mut self, buffer: &mut Vec<f32>) {
+        buffer.append(&mut self.samples);
+    }
+
+    pub fn is_raise_irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // output side(sample rate, filter, pending samples) belongs to the host, not saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.write_bool(self.is_five_step);
+        state.write_bool(self.is_irq_inhibit);
+        state.write_bool(self.frame_irq);
+        state.write_u64(self.frame_cycle);
+        state.write_u64(self.cycle);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.is_five_step = state.read_bool()?;
+        self.is_irq_inhibit = state.read_bool()?;
+        self.frame_irq = state.read_bool()?;
+        self.frame_cycle = state.read_u64()?;
+        self.cycle = state.read_u64()?;
+        Ok(())
+    }
+
+    // 1 CPU cycle
+    pub fn tick(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+
+        // pulse timers are clocked every APU cycle(2 CPU cycles)
+        if (self.cycle & 0x01) == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.process_dmc();
+
+        self.process_frame_counter();
+        self.process_sample();
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4015 => {
+                let mut status = 0x00u8;
+                if self.pulse1.is_active() {
+                    status |= 0x01;
+                }
+                if self.pulse2.is_active() {
+                    status |= 0x02;
+                }
+                if self.triangle.is_active() {
+                    status |= 0x04;
+                }
+                if self.noise.is_active() {
+                    status |= 0x08;
+                }
+                if self.dmc.is_active() {
+                    status |= 0x10;
+                }
+                if self.frame_irq {
+                    status |= 0x40;
+                }
+                if self.dmc.irq {
+                    status |= 0x80;
+                }
+                // reading status clears frame interrupt flag
+                self.frame_irq = false;
+                status
+            }
+            _ => panic!("APU read error:#{:x}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        info!("Apu::write({:04x}, {:02x})", addr, data);
+        match addr {
+            0x4000...0x4003 => self.pulse1.write(addr & 0x03, data),
+            0x4004...0x4007 => self.pulse2.write(addr & 0x03, data),
+            0x4008...0x400B => self.triangle.write(addr & 0x03, data),
+            0x400C...0x400F => self.noise.write(addr & 0x03, data),
+            0x4010...0x4013 => self.dmc.write(addr & 0x03, data),
+            0x4015 => {
+                self.pulse1.set_enabled((data & 0x01) != 0);
+                self.pulse2.set_enabled((data & 0x02) != 0);
+                self.triangle.set_enabled((data & 0x04) != 0);
+                self.noise.set_enabled((data & 0x08) != 0);
+                self.dmc.set_enabled((data & 0x10) != 0);
+            }
+            0x4017 => {
+                self.is_five_step = (data & 0x80) != 0;
+                self.is_irq_inhibit = (data & 0x40) != 0;
+                if self.is_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.is_five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => panic!("APU write error:#{:x}, {:x}", addr, data),
+        }
+    }
+
+    fn process_dmc(&mut self) {
+        if let Some(addr) = self.dmc.fetch_address() {
+            let data = match self.mbc.upgrade() {
+                Some(mbc) => mbc.borrow().read(addr),
+                None => 0x00,
+            };
+            self.dmc.fill_sample_buffer(data);
+        }
+        self.dmc.clock_timer();
+    }
+
+    fn process_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        match self.frame_cycle {
+            FRAME_STEP1 | FRAME_STEP3 => {
+                self.clock_quarter_frame();
+            }
+            FRAME_STEP2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FRAME_STEP4 if !self.is_five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.is_irq_inhibit {
+                    self.frame_irq = true;
+                }
+                self.frame_cycle = 0;
+            }
+            FRAME_STEP5 if self.is_five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // envelopes & triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    // length counters & sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    fn process_sample(&mut self) {
+        self.sample_sum += self.mix();
+        self.sample_count += 1;
+        self.sample_timer += 1.0;
+        if self.sample_timer < self.cycles_per_sample {
+            return;
+        }
+        self.sample_timer -= self.cycles_per_sample;
+
+        let input = self.sample_sum / self.sample_count as f32;
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+
+        let output = self.filter_alpha * (self.filter_prev_output + input - self.filter_prev_input);
+        self.filter_prev_input = input;
+        self.filter_prev_output = output;
+        self.samples.push(output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(apu: &mut Apu, cycles: u64) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn length_counter_counts_down_unless_halted() {
+        let mut length = LengthCounter::new();
+        length.load(0);
+        assert!(!length.is_active(), "loads are ignored while disabled");
+
+        length.set_enabled(true);
+        length.load(0); // 10
+        for _ in 0..9 {
+            length.clock();
+        }
+        assert!(length.is_active());
+        length.clock();
+        assert!(!length.is_active());
+
+        length.load(1); // 254
+        length.halt = true;
+        length.clock();
+        assert_eq!(length.counter, 254);
+        length.set_enabled(false);
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn four_step_sequence_clocks_half_frames_and_raises_irq() {
+        let mut apu = Apu::new();
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x18); // length 2
+
+        tick(&mut apu, FRAME_STEP2 - 1);
+        assert_eq!(apu.read(0x4015) & 0x01, 0x01);
+        tick(&mut apu, FRAME_STEP4 - FRAME_STEP2);
+        assert_eq!(apu.read(0x4015) & 0x01, 0x01);
+        assert!(!apu.is_raise_irq());
+
+        // second half frame empties the counter, IRQ at the end of the sequence
+        tick(&mut apu, 1);
+        assert!(apu.is_raise_irq());
+        assert_eq!(apu.read(0x4015), 0x40);
+        assert!(!apu.is_raise_irq(), "reading $4015 acknowledges frame IRQ");
+    }
+
+    #[test]
+    fn five_step_sequence_has_no_irq() {
+        let mut apu = Apu::new();
+        apu.write(0x4017, 0x80); // clocks a half frame immediately
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x18); // length 2
+
+        tick(&mut apu, FRAME_STEP4);
+        assert_eq!(apu.read(0x4015) & 0x01, 0x01, "no half frame at step 4");
+        tick(&mut apu, FRAME_STEP5 - FRAME_STEP4);
+        assert_eq!(apu.read(0x4015) & 0x01, 0x00);
+        assert!(!apu.is_raise_irq());
+    }
+
+    #[test]
+    fn irq_inhibit_clears_and_blocks_frame_irq() {
+        let mut apu = Apu::new();
+        tick(&mut apu, FRAME_STEP4);
+        assert!(apu.is_raise_irq());
+        apu.write(0x4017, 0x40);
+        assert!(!apu.is_raise_irq());
+        tick(&mut apu, FRAME_STEP4);
+        assert!(!apu.is_raise_irq());
+    }
+
+    #[test]
+    fn envelope_load_state_rejects_out_of_range_volume() {
+        let mut envelope = Envelope::new();
+        envelope.volume = 16;
+        let mut state = StateWriter::payload();
+        envelope.save_state(&mut state);
+        let data = state.into_bytes();
+        assert_eq!(
+            Envelope::new().load_state(&mut StateReader::payload(&data)),
+            Err(StateError::InvalidValue("envelope volume"))
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use nes::apu::{Envelope, LengthCounter};
+use nes::state::{StateError, StateReader, StateWriter};
+
+// timer period(CPU cycles, NTSC)
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub struct Noise {
+    is_short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            is_short_mode: false,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                // --LC VVVV
+                self.length.halt = (data & 0x20) != 0;
+                self.envelope.write(data);
+            }
+            1 => {} // unused
+            2 => {
+                // M--- PPPP
+                self.is_short_mode = (data & 0x80) != 0;
+                self.timer_period = PERIOD_TABLE[(data & 0x0F) as usize];
+            }
+            3 => {
+                // LLLL L---
+                self.length.load(data >> 3);
+                self.envelope.restart();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let tap = if self.is_short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || (self.shift_register & 0x01) != 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_short_mode);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u16(self.shift_register);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_short_mode = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.shift_register = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
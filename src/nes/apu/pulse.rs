@@ -0,0 +1,231 @@
+use nes::apu::{Envelope, LengthCounter};
+use nes::state::{StateError, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+pub struct Pulse {
+    is_channel1: bool, // sweep of pulse1 negates with one's complement
+    duty: u8,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(is_channel1: bool) -> Self {
+        Pulse {
+            is_channel1: is_channel1,
+            duty: 0,
+            sequence: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                // DDLC VVVV
+                self.duty = data >> 6;
+                self.length.halt = (data & 0x20) != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                // EPPP NSSS
+                self.sweep_enabled = (data & 0x80) != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = (data & 0x08) != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                // LLLL LHHH
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.envelope.restart();
+                self.sequence = 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.sweep_target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.is_muted() {
+            return 0;
+        }
+        if DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let mut target = self.timer_period.saturating_sub(change);
+            if self.is_channel1 {
+                target = target.saturating_sub(1);
+            }
+            target
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target_period() > 0x07FF
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.sequence);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_bool(self.sweep_reload);
+        state.write_u8(self.sweep_divider);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.read_u8()? & 0x03;
+        self.sequence = state.read_u8()? & 0x07;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_period = state.read_u8()?;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()?;
+        if self.sweep_shift > 7 {
+            return Err(StateError::InvalidValue("sweep shift"));
+        }
+        self.sweep_reload = state.read_bool()?;
+        self.sweep_divider = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulse_with_period(is_channel1: bool, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(is_channel1);
+        pulse.set_enabled(true);
+        pulse.write(0, 0x9F); // 50% duty, constant volume 15
+        pulse.write(2, (period & 0xFF) as u8);
+        pulse.write(3, (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn sweep_mutes_low_period_and_overflowing_target() {
+        assert!(pulse_with_period(true, 7).is_muted());
+        assert!(!pulse_with_period(true, 8).is_muted());
+
+        // target period is checked even while sweep is disabled
+        let mut pulse = pulse_with_period(true, 0x600);
+        pulse.write(1, 0x02); // shift 2: 0x600 + 0x180
+        assert!(!pulse.is_muted());
+        pulse.write(1, 0x01); // shift 1: 0x600 + 0x300
+        assert!(pulse.is_muted());
+        pulse.sequence = 1;
+        assert_eq!(pulse.output(), 0);
+
+        pulse.write(1, 0x09); // negated
+        assert!(!pulse.is_muted());
+        assert_eq!(pulse.output(), 15);
+    }
+
+    #[test]
+    fn sweep_negate_differs_between_channels() {
+        let mut pulse1 = pulse_with_period(true, 0x100);
+        let mut pulse2 = pulse_with_period(false, 0x100);
+        pulse1.write(1, 0x89); // enabled, period 0, negate, shift 1
+        pulse2.write(1, 0x89);
+        pulse1.clock_half_frame();
+        pulse2.clock_half_frame();
+        assert_eq!(pulse1.timer_period, 0x7F);
+        assert_eq!(pulse2.timer_period, 0x80);
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_sweep_shift() {
+        let mut pulse = Pulse::new(true);
+        pulse.sweep_shift = 16;
+        let mut state = StateWriter::payload();
+        pulse.save_state(&mut state);
+        let data = state.into_bytes();
+        assert_eq!(
+            Pulse::new(true).load_state(&mut StateReader::payload(&data)),
+            Err(StateError::InvalidValue("sweep shift"))
+        );
+    }
+}
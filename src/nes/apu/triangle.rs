@@ -0,0 +1,116 @@
+use nes::apu::LengthCounter;
+use nes::state::{StateError, StateReader, StateWriter};
+
+const SEQUENCE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct Triangle {
+    is_control: bool, // also halts length counter
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    sequence: u8,
+    length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            is_control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            timer_period: 0,
+            timer: 0,
+            sequence: 0,
+            length: LengthCounter::new(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                // CRRR RRRR
+                self.is_control = (data & 0x80) != 0;
+                self.length.halt = self.is_control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {} // unused
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                // LLLL LHHH
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.length.is_active()
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.is_active() {
+                self.sequence = (self.sequence + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.is_control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE_TABLE[self.sequence as usize]
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.is_control);
+        state.write_u8(self.linear_reload_value);
+        state.write_u8(self.linear_counter);
+        state.write_bool(self.linear_reload);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.sequence);
+        self.length.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.is_control = state.read_bool()?;
+        self.linear_reload_value = state.read_u8()?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_reload = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.sequence = state.read_u8()? & 0x1F;
+        self.length.load_state(state)
+    }
+}
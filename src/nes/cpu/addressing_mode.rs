@@ -8,12 +8,13 @@ pub trait AddressingMode {
     fn write(&self, cpu: &mut Cpu, data: u8) {
         unimplemented!()
     }
-    fn read16(&self, cpu: &mut Cpu) -> u16 {
-        unimplemented!()
-    }
     fn read16_addr(&self, cpu: &mut Cpu) -> u16 {
         unimplemented!()
     }
+    // address before indexing, None for modes without a memory operand
+    fn base_addr(&self) -> Option<u16> {
+        None
+    }
 
     fn length(&self) -> u16 {
         unimplemented!()
@@ -32,6 +33,7 @@ impl AddressingMode for NoAccessAddressingMode {
 
 pub struct MemoryAddressingMode {
     addr: u16,
+    base: u16, // address before indexing
     size: u16,
 }
 
@@ -39,6 +41,16 @@ impl MemoryAddressingMode {
     pub fn new(addr: u16, size: u16) -> Self {
         MemoryAddressingMode {
             addr: addr,
+            base: addr,
+            size: size,
+        }
+    }
+
+    // indexed addressing(abs,X / abs,Y / (ind),Y)
+    pub fn indexed(base: u16, index: u8, size: u16) -> Self {
+        MemoryAddressingMode {
+            addr: base.wrapping_add(index as u16),
+            base: base,
             size: size,
         }
     }
@@ -48,21 +60,21 @@ impl AddressingMode for MemoryAddressingMode {
     fn read(&self, cpu: &mut Cpu) -> u8 {
         cpu.mbc.borrow_mut().read(self.addr)
     }
-    fn read16(&self, cpu: &mut Cpu) -> u16 {
-        let mbc = cpu.mbc.borrow_mut();
-        let low = mbc.read(self.addr) as u16;
-        let high = mbc.read(self.addr + 1) as u16;
-        high << 8 | low
-    }
     fn read16_addr(&self, _: &mut Cpu) -> u16 {
         self.addr
     }
+    fn base_addr(&self) -> Option<u16> {
+        Some(self.base)
+    }
     fn write(&self, cpu: &mut Cpu, data: u8) {
         cpu.mbc.borrow_mut().write(self.addr, data)
     }
     fn length(&self) -> u16 {
         self.size
     }
+    fn is_page_crossed(&self) -> bool {
+        (self.base & 0xFF00) != (self.addr & 0xFF00)
+    }
 }
 
 pub struct ImmediateAddressingMode {
@@ -83,9 +95,6 @@ impl AddressingMode for ImmediateAddressingMode {
     fn read(&self, _: &mut Cpu) -> u8 {
         self.value
     }
-    fn read16(&self, _: &mut Cpu) -> u16 {
-        unimplemented!()
-    }
     fn write(&self, _: &mut Cpu, _: u8) {
         unimplemented!()
     }
@@ -1,7 +1,11 @@
 mod addressing_mode;
+mod trace;
 
 use nes::cpu::addressing_mode::*;
+use nes::disasm::Instruction;
+use nes::disasm::Mode::{self, *};
 use nes::mbc::Mbc;
+use nes::state::{StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -15,7 +19,14 @@ pub struct Cpu {
     pub p: u8,   // processor status register
     pub mbc: Rc<RefCell<Box<Mbc>>>,
     pub cycle: u64,
-    //
+    // I flag seen by the interrupt polling.
+    // CLI/SEI/PLP change the flag after the polling, so it is delayed by one instruction.
+    irq_masked: bool,
+    // nestest style lines of executed instructions, when tracing
+    trace: Option<Rc<RefCell<Vec<String>>>>,
+    // action of the last dispatched opcode, checked against OPCODES
+    #[cfg(test)]
+    last_action: &'static str,
 }
 
 const FLAG_CRY: u8 = 0x01; // carry flag
@@ -77,6 +88,10 @@ macro_rules !branch {
                 let offset = $addr.read($self) as i8 as i32;
                 let jump_addr = (($self.pc as i32) + offset) as u16 + 1;
                 info!("{} Jump pc:{:x} -> {:x}", $name, $self.pc, jump_addr);
+                // taken branch costs 1 cycle, 2 when it crosses a page
+                let next_addr = $self.pc + 1;
+                let page_cycle = if (next_addr & 0xFF00) != (jump_addr & 0xFF00) { 2 } else { 1 };
+                $self.cycle = $self.cycle.wrapping_add(page_cycle);
                 $self.pc = jump_addr;
                 true
             } else {
@@ -94,6 +109,10 @@ macro_rules !instruction {
      $cycle:expr,
      $page_cycle:expr) => {
          {
+            #[cfg(test)]
+            {
+                $self.last_action = stringify!($action);
+            }
             let m = $self.$addressing_mode();
             $self.cycle = $self.cycle.wrapping_add($cycle);
             if $page_cycle != 0 && m.is_page_crossed() {
@@ -116,6 +135,10 @@ impl Cpu {
             p: 0,
             mbc: mbc,
             cycle: 0,
+            irq_masked: false,
+            trace: None,
+            #[cfg(test)]
+            last_action: "",
         }
     }
 
@@ -127,15 +150,17 @@ impl Cpu {
         self.set_flag(FLAG_IRQ, false);
         self.set_flag(FLAG_DEC, false);
         self.set_flag(FLAG_BRK, false);
-        self.set_flag(FLAG_RSV, false);
+        self.set_flag(FLAG_RSV, true);
         self.set_flag(FLAG_OVF, false);
         self.set_flag(FLAG_NEG, false);
         self.pc = pc;
     }
 
-    fn brk<T: AddressingMode>(&mut self, addr: T) -> bool {
+    fn brk<T: AddressingMode>(&mut self, _addr: T) -> bool {
         info!("opcode:BRK");
-        self.do_irq("irq");
+        // skip padding byte
+        self.pc += 1;
+        self.do_irq("irq", true);
         false
     }
 
@@ -147,8 +172,16 @@ impl Cpu {
 
     fn slo<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SLO");
+        let data = addr.read(self);
+        let result = data << 1;
+        self.set_flag(FLAG_CRY, (data & 0x80) == 0x80);
+        addr.write(self, result);
+        let a = self.a | result;
+        self.a = a;
+        self.set_negative_flag(a);
+        self.set_zero_flag(a);
         self.pc += addr.length();
-        unimplemented!();
+        true
     }
     fn nop<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:NOP");
@@ -157,8 +190,13 @@ impl Cpu {
     }
     fn anc<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:ANC");
+        let result = self.a & addr.read(self);
+        self.a = result;
+        self.set_negative_flag(result);
+        self.set_zero_flag(result);
+        self.set_flag(FLAG_CRY, (result & 0x80) == 0x80);
         self.pc += addr.length();
-        unimplemented!();
+        true
     }
     fn clc<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:CLC");
@@ -213,32 +251,36 @@ impl Cpu {
     }
     fn jsr<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:JSR");
-        self.set_flag(FLAG_DEC, true);
         let pc = self.pc;
         self.push16(pc + addr.length() - 1);
         self.pc = addr.read16_addr(self);
         false
     }
-    fn rts<T: AddressingMode>(&mut self, addr: T) -> bool {
+    fn rts<T: AddressingMode>(&mut self, _addr: T) -> bool {
         info!("opcode:RTS");
         let return_addr = self.pop16();
         info!("self.pc({:x}) => {:x}", self.pc, return_addr);
         self.pc = return_addr + 1;
         false
     }
-    fn rti<T: AddressingMode>(&mut self, addr: T) -> bool {
+    fn rti<T: AddressingMode>(&mut self, _addr: T) -> bool {
         info!("opcode:RTI");
-        self.p = self.pop();
+        self.p = (self.pop() & !FLAG_BRK) | FLAG_RSV;
         let return_addr = self.pop16();
         info!("self.pc({:x}) => {:x}", self.pc, return_addr);
         self.pc = return_addr;
         false
     }
 
-    fn do_irq(&mut self, irq_name: &str) {
+    // B flag only exists on the stack: set by BRK, clear by IRQ/NMI
+    fn do_irq(&mut self, irq_name: &str, is_brk: bool) {
         let pc = self.pc;
         self.push16(pc);
-        let p = self.p;
+        let p = if is_brk {
+            self.p | FLAG_BRK | FLAG_RSV
+        } else {
+            (self.p & !FLAG_BRK) | FLAG_RSV
+        };
         self.push(p);
         self.set_flag(FLAG_IRQ, true);
         self.pc = self.vector(irq_name);
@@ -349,21 +391,8 @@ impl Cpu {
     // caluculate oprators
     fn adc<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:ADC");
-        let value = addr.read(self) as u16;
-        let target = self.a as u16;
-        let mut result = target.wrapping_add(value);
-        if self.get_flag(FLAG_CRY) {
-            result = result.wrapping_add(1);
-        }
-        self.set_negative_flag(result as u8);
-        self.set_zero_flag(result as u8);
-        self.set_flag(FLAG_CRY, (result & 0x0100) != 0);
-        self.set_flag(
-            FLAG_OVF,
-            (target ^ value) & 0x80 == 0 && (target ^ result) & 0x80 == 0x80,
-        );
-        self.a = result as u8;
-
+        let value = addr.read(self);
+        self.add_with_carry(value);
         self.pc += addr.length();
         true
     }
@@ -526,14 +555,43 @@ impl Cpu {
     fn sre<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SRE");
         let data = addr.read(self);
+        let result = data >> 1;
         self.set_flag(FLAG_CRY, (data & 0x01) != 0);
-        addr.write(self, data >> 1);
+        addr.write(self, result);
+        let a = self.a ^ result;
+        self.a = a;
+        self.set_negative_flag(a);
+        self.set_zero_flag(a);
         self.pc += addr.length();
         true
     }
     fn sbc<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SBC");
-        let value = addr.read(self) as u16;
+        let value = addr.read(self);
+        self.subtract_with_carry(value);
+        self.pc += addr.length();
+        true
+    }
+
+    fn add_with_carry(&mut self, value: u8) {
+        let value = value as u16;
+        let target = self.a as u16;
+        let mut result = target.wrapping_add(value);
+        if self.get_flag(FLAG_CRY) {
+            result = result.wrapping_add(1);
+        }
+        self.set_negative_flag(result as u8);
+        self.set_zero_flag(result as u8);
+        self.set_flag(FLAG_CRY, (result & 0x0100) != 0);
+        self.set_flag(
+            FLAG_OVF,
+            (target ^ value) & 0x80 == 0 && (target ^ result) & 0x80 == 0x80,
+        );
+        self.a = result as u8;
+    }
+
+    fn subtract_with_carry(&mut self, value: u8) {
+        let value = value as u16;
         let target = self.a as u16;
         let mut result = target.wrapping_sub(value);
         if !self.get_flag(FLAG_CRY) {
@@ -547,8 +605,6 @@ impl Cpu {
             (target ^ value) & 0x80 != 0 && (target ^ result) & 0x80 == 0x80,
         );
         self.a = result as u8;
-        self.pc += addr.length();
-        true
     }
 
     // stack
@@ -579,7 +635,7 @@ impl Cpu {
     }
     fn plp<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:PLP");
-        self.p = self.pop();
+        self.p = (self.pop() & !FLAG_BRK) | FLAG_RSV;
         self.pc += addr.length();
         true
     }
@@ -612,67 +668,168 @@ impl Cpu {
 
     fn rla<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:RLA");
-        unimplemented!();
+        let data = addr.read(self);
+        let mut result = data << 1;
+        if self.get_flag(FLAG_CRY) {
+            result |= 0x01;
+        }
+        self.set_flag(FLAG_CRY, (data & 0x80) == 0x80);
+        addr.write(self, result);
+        let a = self.a & result;
+        self.a = a;
+        self.set_negative_flag(a);
+        self.set_zero_flag(a);
+        self.pc += addr.length();
+        true
     }
     fn rra<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:RRA");
-        unimplemented!();
+        let data = addr.read(self);
+        let mut result = data >> 1;
+        if self.get_flag(FLAG_CRY) {
+            result |= 0x80;
+        }
+        self.set_flag(FLAG_CRY, (data & 0x01) == 0x01);
+        addr.write(self, result);
+        self.add_with_carry(result);
+        self.pc += addr.length();
+        true
     }
     fn alr<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:ALR");
-        unimplemented!();
+        let data = self.a & addr.read(self);
+        let result = data >> 1;
+        self.set_flag(FLAG_CRY, (data & 0x01) == 0x01);
+        self.set_negative_flag(result);
+        self.set_zero_flag(result);
+        self.a = result;
+        self.pc += addr.length();
+        true
     }
     fn arr<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:ARR");
-        unimplemented!();
+        let data = self.a & addr.read(self);
+        let mut result = data >> 1;
+        if self.get_flag(FLAG_CRY) {
+            result |= 0x80;
+        }
+        // carry from bit 6, overflow from bit 6 xor bit 5
+        self.set_flag(FLAG_CRY, (result & 0x40) == 0x40);
+        self.set_flag(FLAG_OVF, ((result >> 6) ^ (result >> 5)) & 0x01 == 0x01);
+        self.set_negative_flag(result);
+        self.set_zero_flag(result);
+        self.a = result;
+        self.pc += addr.length();
+        true
     }
     fn sax<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SAX");
-        unimplemented!();
-    }
-    fn say<T: AddressingMode>(&mut self, addr: T) -> bool {
-        info!("opcode:SAY");
-        unimplemented!();
+        let value = self.a & self.x;
+        addr.write(self, value);
+        self.pc += addr.length();
+        true
     }
     fn xaa<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:XAA");
-        unimplemented!();
+        // unstable on real hardware, use the common magic constant 0xEE
+        let result = (self.a | 0xEE) & self.x & addr.read(self);
+        self.a = result;
+        self.set_negative_flag(result);
+        self.set_zero_flag(result);
+        self.pc += addr.length();
+        true
     }
     fn ahx<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:AHX");
-        unimplemented!();
+        let value = self.a & self.x;
+        self.store_and_high_byte(&addr, value);
+        self.pc += addr.length();
+        true
     }
     fn tas<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:TAS");
-        unimplemented!();
+        let value = self.a & self.x;
+        self.s = value;
+        self.store_and_high_byte(&addr, value);
+        self.pc += addr.length();
+        true
     }
     fn shx<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SHX");
-        unimplemented!();
+        let value = self.x;
+        self.store_and_high_byte(&addr, value);
+        self.pc += addr.length();
+        true
     }
     fn shy<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:SHY");
-        unimplemented!();
+        let value = self.y;
+        self.store_and_high_byte(&addr, value);
+        self.pc += addr.length();
+        true
     }
     fn lax<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:LAX");
-        unimplemented!();
+        let value = addr.read(self);
+        self.a = value;
+        self.x = value;
+        self.set_negative_flag(value);
+        self.set_zero_flag(value);
+        self.pc += addr.length();
+        true
     }
     fn las<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:LAS");
-        unimplemented!();
+        let value = addr.read(self) & self.s;
+        self.a = value;
+        self.x = value;
+        self.s = value;
+        self.set_negative_flag(value);
+        self.set_zero_flag(value);
+        self.pc += addr.length();
+        true
     }
     fn dcp<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:DCP");
-        unimplemented!();
+        let mut value = addr.read(self);
+        value = value.wrapping_sub(1);
+        addr.write(self, value);
+        cmp!(self, self.a, value);
+        self.pc += addr.length();
+        true
     }
     fn axs<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:AXS");
-        unimplemented!();
+        let target = self.a & self.x;
+        let value = addr.read(self);
+        cmp!(self, target, value);
+        self.x = target.wrapping_sub(value);
+        self.pc += addr.length();
+        true
     }
     fn isc<T: AddressingMode>(&mut self, addr: T) -> bool {
         info!("opcode:ISC");
-        unimplemented!();
+        let mut value = addr.read(self);
+        value = value.wrapping_add(1);
+        addr.write(self, value);
+        self.subtract_with_carry(value);
+        self.pc += addr.length();
+        true
+    }
+
+    // SHX/SHY/AHX/TAS store (register & (high byte of base address + 1)).
+    // when the index crosses a page, the stored value also replaces the high byte of the address.
+    fn store_and_high_byte<T: AddressingMode>(&mut self, addr: &T, value: u8) {
+        let base = match addr.base_addr() {
+            Some(base) => base,
+            None => return, // these opcodes only exist with indexed memory operands
+        };
+        let value = value & ((base >> 8) as u8).wrapping_add(1);
+        let mut target = addr.read16_addr(self);
+        if addr.is_page_crossed() {
+            target = (target & 0x00FF) | ((value as u16) << 8);
+        }
+        self.mbc.borrow_mut().write(target, value);
     }
 
     // addressing mode
@@ -685,8 +842,10 @@ impl Cpu {
     }
 
     fn indirect(&mut self) -> MemoryAddressingMode {
-        let addr = self.read16(self.pc);
-        let addr = self.read16(addr);
+        let pointer = self.read16(self.pc);
+        // high byte is fetched without carry: JMP ($02FF) reads $02FF and $0200
+        let high_pointer = (pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF);
+        let addr = (self.read(high_pointer) as u16) << 8 | self.read(pointer) as u16;
         MemoryAddressingMode::new(addr, 2)
     }
 
@@ -703,12 +862,12 @@ impl Cpu {
 
     fn indirecty(&mut self) -> MemoryAddressingMode {
         let operand = self.read(self.pc) as u16;
-        let y = self.y as u16;
+        let y = self.y;
         let low_addr = operand;
         let high_addr = (operand + 1) & 0xFF;
         let low = self.read(low_addr) as u16;
         let high = (self.read(high_addr) as u16) << 8;
-        MemoryAddressingMode::new(low.wrapping_add(high).wrapping_add(y), 1)
+        MemoryAddressingMode::indexed(low | high, y, 1)
     }
 
     fn zeropage(&mut self) -> MemoryAddressingMode {
@@ -738,13 +897,13 @@ impl Cpu {
     }
 
     fn absolutex(&mut self) -> MemoryAddressingMode {
-        let addr = self.read16(self.pc).wrapping_add(self.x as u16);
-        MemoryAddressingMode::new(addr, 2)
+        let base = self.read16(self.pc);
+        MemoryAddressingMode::indexed(base, self.x, 2)
     }
 
     fn absolutey(&mut self) -> MemoryAddressingMode {
-        let addr = self.read16(self.pc).wrapping_add(self.y as u16);
-        MemoryAddressingMode::new(addr, 2)
+        let base = self.read16(self.pc);
+        MemoryAddressingMode::indexed(base, self.y, 2)
     }
 
     pub fn cycle(&self) -> u64 {
@@ -758,20 +917,33 @@ impl Cpu {
         if self.process_nmi() {
             return;
         }
+        if self.process_irq() {
+            return;
+        }
+
+        if let Some(ref trace) = self.trace {
+            let ppu_position = self.mbc.borrow().ppu_position();
+            trace.borrow_mut().push(trace::format(self, ppu_position));
+        }
 
         let before_status = self.clone();
         let opcode = self.read(self.pc);
         self.pc += 1;
         self.process_opcode(opcode);
 
+        self.irq_masked = match opcode {
+            0x58 | 0x78 | 0x28 => before_status.get_flag(FLAG_IRQ), // CLI, SEI, PLP
+            _ => self.get_flag(FLAG_IRQ),
+        };
+
         self.print_diff(before_status);
     }
 
     fn process_opcode(&mut self, opcode: u8) {
-        let cont = match opcode {
+        match opcode {
             0x00 => instruction!(self, implicit, brk, 7, 0),
             0x01 => instruction!(self, indirectx, ora, 6, 0),
-            0x02 => instruction!(self, immediate, kil, 2, 0),
+            0x02 => instruction!(self, implicit, kil, 2, 0),
             0x03 => instruction!(self, indirectx, slo, 8, 0),
             0x04 => instruction!(self, zeropage, nop, 3, 0),
             0x05 => instruction!(self, zeropage, ora, 3, 0),
@@ -787,7 +959,7 @@ impl Cpu {
             0x0F => instruction!(self, absolute, slo, 6, 0),
             0x10 => instruction!(self, immediate, bpl, 2, 1),
             0x11 => instruction!(self, indirecty, ora, 5, 1),
-            0x12 => instruction!(self, immediate, kil, 2, 0),
+            0x12 => instruction!(self, implicit, kil, 2, 0),
             0x13 => instruction!(self, indirecty, slo, 8, 0),
             0x14 => instruction!(self, zeropagex, nop, 4, 0),
             0x15 => instruction!(self, zeropagex, ora, 4, 0),
@@ -795,7 +967,7 @@ impl Cpu {
             0x17 => instruction!(self, zeropagex, slo, 6, 0),
             0x18 => instruction!(self, implicit, clc, 2, 0),
             0x19 => instruction!(self, absolutey, ora, 4, 1),
-            0x1A => instruction!(self, immediate, nop, 2, 0),
+            0x1A => instruction!(self, implicit, nop, 2, 0),
             0x1B => instruction!(self, absolutey, slo, 7, 0),
             0x1C => instruction!(self, absolutex, nop, 4, 1),
             0x1D => instruction!(self, absolutex, ora, 4, 1),
@@ -803,7 +975,7 @@ impl Cpu {
             0x1F => instruction!(self, absolutex, slo, 7, 0),
             0x20 => instruction!(self, absolute, jsr, 6, 0),
             0x21 => instruction!(self, indirectx, and, 6, 0),
-            0x22 => instruction!(self, immediate, kil, 2, 0),
+            0x22 => instruction!(self, implicit, kil, 2, 0),
             0x23 => instruction!(self, indirectx, rla, 8, 0),
             0x24 => instruction!(self, zeropage, bit, 3, 0),
             0x25 => instruction!(self, zeropage, and, 3, 0),
@@ -819,7 +991,7 @@ impl Cpu {
             0x2F => instruction!(self, absolute, rla, 6, 0),
             0x30 => instruction!(self, immediate, bmi, 2, 1),
             0x31 => instruction!(self, indirecty, and, 5, 1),
-            0x32 => instruction!(self, immediate, kil, 2, 0),
+            0x32 => instruction!(self, implicit, kil, 2, 0),
             0x33 => instruction!(self, indirecty, rla, 8, 0),
             0x34 => instruction!(self, zeropagex, nop, 4, 0),
             0x35 => instruction!(self, zeropagex, and, 4, 0),
@@ -827,7 +999,7 @@ impl Cpu {
             0x37 => instruction!(self, zeropagex, rla, 6, 0),
             0x38 => instruction!(self, implicit, sec, 2, 0),
             0x39 => instruction!(self, absolutey, and, 4, 1),
-            0x3A => instruction!(self, immediate, nop, 2, 0),
+            0x3A => instruction!(self, implicit, nop, 2, 0),
             0x3B => instruction!(self, absolutey, rla, 7, 0),
             0x3C => instruction!(self, absolutex, nop, 4, 1),
             0x3D => instruction!(self, absolutex, and, 4, 1),
@@ -835,7 +1007,7 @@ impl Cpu {
             0x3F => instruction!(self, absolutex, rla, 7, 0),
             0x40 => instruction!(self, implicit, rti, 6, 0),
             0x41 => instruction!(self, indirectx, eor, 6, 0),
-            0x42 => instruction!(self, immediate, kil, 2, 0),
+            0x42 => instruction!(self, implicit, kil, 2, 0),
             0x43 => instruction!(self, indirectx, sre, 8, 0),
             0x44 => instruction!(self, zeropage, nop, 3, 0),
             0x45 => instruction!(self, zeropage, eor, 3, 0),
@@ -851,7 +1023,7 @@ impl Cpu {
             0x4F => instruction!(self, absolute, sre, 6, 0),
             0x50 => instruction!(self, immediate, bvc, 2, 1),
             0x51 => instruction!(self, indirecty, eor, 5, 1),
-            0x52 => instruction!(self, immediate, kil, 2, 0),
+            0x52 => instruction!(self, implicit, kil, 2, 0),
             0x53 => instruction!(self, indirecty, sre, 8, 0),
             0x54 => instruction!(self, zeropagex, nop, 4, 0),
             0x55 => instruction!(self, zeropagex, eor, 4, 0),
@@ -859,7 +1031,7 @@ impl Cpu {
             0x57 => instruction!(self, zeropagex, sre, 6, 0),
             0x58 => instruction!(self, implicit, cli, 2, 0),
             0x59 => instruction!(self, absolutey, eor, 4, 1),
-            0x5A => instruction!(self, immediate, nop, 2, 0),
+            0x5A => instruction!(self, implicit, nop, 2, 0),
             0x5B => instruction!(self, absolutey, sre, 7, 0),
             0x5C => instruction!(self, absolutex, nop, 4, 1),
             0x5D => instruction!(self, absolutex, eor, 4, 1),
@@ -867,7 +1039,7 @@ impl Cpu {
             0x5F => instruction!(self, absolutex, sre, 7, 0),
             0x60 => instruction!(self, implicit, rts, 6, 0),
             0x61 => instruction!(self, indirectx, adc, 6, 0),
-            0x62 => instruction!(self, immediate, kil, 2, 0),
+            0x62 => instruction!(self, implicit, kil, 2, 0),
             0x63 => instruction!(self, indirectx, rra, 8, 0),
             0x64 => instruction!(self, zeropage, nop, 3, 0),
             0x65 => instruction!(self, zeropage, adc, 3, 0),
@@ -883,7 +1055,7 @@ impl Cpu {
             0x6F => instruction!(self, absolute, rra, 6, 0),
             0x70 => instruction!(self, immediate, bvs, 2, 1),
             0x71 => instruction!(self, indirecty, adc, 5, 1),
-            0x72 => instruction!(self, immediate, kil, 2, 0),
+            0x72 => instruction!(self, implicit, kil, 2, 0),
             0x73 => instruction!(self, indirecty, rra, 8, 0),
             0x74 => instruction!(self, zeropagex, nop, 4, 0),
             0x75 => instruction!(self, zeropagex, adc, 4, 0),
@@ -891,7 +1063,7 @@ impl Cpu {
             0x77 => instruction!(self, zeropagex, rra, 6, 0),
             0x78 => instruction!(self, implicit, sei, 2, 0),
             0x79 => instruction!(self, absolutey, adc, 4, 1),
-            0x7A => instruction!(self, immediate, nop, 2, 0),
+            0x7A => instruction!(self, implicit, nop, 2, 0),
             0x7B => instruction!(self, absolutey, rra, 7, 0),
             0x7C => instruction!(self, absolutex, nop, 4, 1),
             0x7D => instruction!(self, absolutex, adc, 4, 1),
@@ -915,7 +1087,7 @@ impl Cpu {
             0x8F => instruction!(self, absolute, sax, 4, 0),
             0x90 => instruction!(self, immediate, bcc, 2, 1),
             0x91 => instruction!(self, indirecty, sta, 6, 0),
-            0x92 => instruction!(self, immediate, kil, 2, 0),
+            0x92 => instruction!(self, implicit, kil, 2, 0),
             0x93 => instruction!(self, indirecty, ahx, 6, 0),
             0x94 => instruction!(self, zeropagex, sty, 4, 0),
             0x95 => instruction!(self, zeropagex, sta, 4, 0),
@@ -924,7 +1096,7 @@ impl Cpu {
             0x98 => instruction!(self, implicit, tya, 2, 0),
             0x99 => instruction!(self, absolutey, sta, 5, 0),
             0x9A => instruction!(self, implicit, txs, 2, 0),
-            0x9B => instruction!(self, immediate, tas, 5, 0),
+            0x9B => instruction!(self, absolutey, tas, 5, 0),
             0x9C => instruction!(self, absolutex, shy, 5, 0),
             0x9D => instruction!(self, absolutex, sta, 5, 0),
             0x9E => instruction!(self, absolutey, shx, 5, 0),
@@ -947,7 +1119,7 @@ impl Cpu {
             0xAF => instruction!(self, absolute, lax, 4, 0),
             0xB0 => instruction!(self, immediate, bcs, 2, 1),
             0xB1 => instruction!(self, indirecty, lda, 5, 1),
-            0xB2 => instruction!(self, immediate, kil, 2, 0),
+            0xB2 => instruction!(self, implicit, kil, 2, 0),
             0xB3 => instruction!(self, indirecty, lax, 5, 1),
             0xB4 => instruction!(self, zeropagex, ldy, 4, 0),
             0xB5 => instruction!(self, zeropagex, lda, 4, 0),
@@ -979,7 +1151,7 @@ impl Cpu {
             0xCF => instruction!(self, absolute, dcp, 6, 0),
             0xD0 => instruction!(self, immediate, bne, 2, 1),
             0xD1 => instruction!(self, indirecty, cmp, 5, 1),
-            0xD2 => instruction!(self, immediate, kil, 2, 0),
+            0xD2 => instruction!(self, implicit, kil, 2, 0),
             0xD3 => instruction!(self, indirecty, dcp, 8, 0),
             0xD4 => instruction!(self, zeropagex, nop, 4, 0),
             0xD5 => instruction!(self, zeropagex, cmp, 4, 0),
@@ -987,7 +1159,7 @@ impl Cpu {
             0xD7 => instruction!(self, zeropagex, dcp, 6, 0),
             0xD8 => instruction!(self, implicit, cld, 2, 0),
             0xD9 => instruction!(self, absolutey, cmp, 4, 1),
-            0xDA => instruction!(self, immediate, nop, 2, 0),
+            0xDA => instruction!(self, implicit, nop, 2, 0),
             0xDB => instruction!(self, absolutey, dcp, 7, 0),
             0xDC => instruction!(self, absolutex, nop, 4, 1),
             0xDD => instruction!(self, absolutex, cmp, 4, 1),
@@ -1011,7 +1183,7 @@ impl Cpu {
             0xEF => instruction!(self, absolute, isc, 6, 0),
             0xF0 => instruction!(self, immediate, beq, 2, 1),
             0xF1 => instruction!(self, indirecty, sbc, 5, 1),
-            0xF2 => instruction!(self, immediate, kil, 2, 0),
+            0xF2 => instruction!(self, implicit, kil, 2, 0),
             0xF3 => instruction!(self, indirecty, isc, 8, 0),
             0xF4 => instruction!(self, zeropagex, nop, 4, 0),
             0xF5 => instruction!(self, zeropagex, sbc, 4, 0),
@@ -1019,13 +1191,12 @@ impl Cpu {
             0xF7 => instruction!(self, zeropagex, isc, 6, 0),
             0xF8 => instruction!(self, implicit, sed, 2, 0),
             0xF9 => instruction!(self, absolutey, sbc, 4, 1),
-            0xFA => instruction!(self, immediate, nop, 2, 0),
+            0xFA => instruction!(self, implicit, nop, 2, 0),
             0xFB => instruction!(self, absolutey, isc, 7, 0),
             0xFC => instruction!(self, absolutex, nop, 4, 1),
             0xFD => instruction!(self, absolutex, sbc, 4, 1),
             0xFE => instruction!(self, absolutex, inc, 7, 0),
             0xFF => instruction!(self, absolutex, isc, 7, 0),
-            _ => panic!("none opcode:{:x}", opcode),
         };
     }
 
@@ -1060,6 +1231,7 @@ impl Cpu {
             "a:{:02x}  x:{:02x}  y:{:02x}  s:{:02x}",
             self.a, self.x, self.y, self.s
         );
+        info!("{}", self.disassemble_next());
         info!(
             "p[{:x}] CRY:{}, ZER:{}, IRQ:{}, DEC:{}, BRK:{}, RSV:{}, OVF:{}, NEG:{}",
             self.p,
@@ -1074,6 +1246,14 @@ impl Cpu {
         );
     }
 
+    // instruction at pc, read without side effects
+    fn disassemble_next(&self) -> String {
+        let mbc = self.mbc.borrow();
+        let read = |addr| mbc.peek(addr).unwrap_or(0xFF);
+        let instruction = Instruction::decode(self.pc, read);
+        format!("{:04x}: {}", self.pc, instruction.format_resolved(self.x, self.y, read, None))
+    }
+
     fn read(&self, addr: u16) -> u8 {
         self.mbc.borrow_mut().read(addr)
     }
@@ -1128,7 +1308,7 @@ impl Cpu {
         }
     }
 
-    fn get_flag(&mut self, flag: u8) -> bool {
+    fn get_flag(&self, flag: u8) -> bool {
         (self.p & flag) != 0
     }
 
@@ -1140,10 +1320,6 @@ impl Cpu {
         self.set_flag(FLAG_ZER, value == 0);
     }
 
-    fn set_overflow_flag(&mut self, before: u8, after: u8) {
-        self.set_flag(FLAG_OVF, (before & 0x80) == 0 && (after & 0x80) == 0x80);
-    }
-
     fn process_nmi(&mut self) -> bool {
         let need_irq = {
             let mbc = self.mbc.borrow_mut();
@@ -1156,7 +1332,26 @@ impl Cpu {
 
         if need_irq {
             info!("do_irq");
-            self.do_irq("nmi");
+            self.do_irq("nmi", false);
+            self.irq_masked = true;
+            self.cycle = self.cycle.wrapping_add(7);
+            true
+        } else {
+            false
+        }
+    }
+
+    // IRQ is level triggered, taken while any source holds the line
+    fn process_irq(&mut self) -> bool {
+        if self.irq_masked {
+            return false;
+        }
+        let raised = self.mbc.borrow().is_raise_irq();
+        if raised {
+            info!("do_irq");
+            self.do_irq("irq", false);
+            self.irq_masked = true;
+            self.cycle = self.cycle.wrapping_add(7);
             true
         } else {
             false
@@ -1166,8 +1361,49 @@ impl Cpu {
     pub fn reset(&mut self) {
         self.pc = self.vector("reset");
         info!("reset vector:{:x}", self.pc);
+        self.set_flag(FLAG_IRQ, true);
+        self.irq_masked = true;
 
-        self.s = 0xFF;
+        // reset runs the interrupt sequence with writes suppressed, S is decremented 3 times
+        self.s = 0xFD;
+        self.cycle = self.cycle.wrapping_add(7);
+    }
+
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = if enabled {
+            Some(Rc::new(RefCell::new(vec![])))
+        } else {
+            None
+        };
+    }
+
+    pub fn take_trace(&mut self, buffer: &mut Vec<String>) {
+        if let Some(ref trace) = self.trace {
+            buffer.extend(trace.borrow_mut().drain(..));
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.a);
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u16(self.pc);
+        state.write_u8(self.s);
+        state.write_u8(self.p);
+        state.write_u64(self.cycle);
+        state.write_bool(self.irq_masked);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.a = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.pc = state.read_u16()?;
+        self.s = state.read_u8()?;
+        self.p = state.read_u8()?;
+        self.cycle = state.read_u64()?;
+        self.irq_masked = state.read_bool()?;
+        Ok(())
     }
 
     fn vector(&self, name: &str) -> u16 {
@@ -1180,3 +1416,806 @@ impl Cpu {
         self.read16(addr)
     }
 }
+
+// (opcode, mnemonic, addressing mode, cycles) in opcode order, for the disassembler.
+// cycles don't include the extra ones for page crossing and taken branches
+pub const OPCODES: [(u8, &str, Mode, u8); 256] = [
+    (0x00, "BRK", Implied, 7),
+    (0x01, "ORA", IndirectX, 6),
+    (0x02, "KIL", Implied, 2),
+    (0x03, "SLO", IndirectX, 8),
+    (0x04, "NOP", ZeroPage, 3),
+    (0x05, "ORA", ZeroPage, 3),
+    (0x06, "ASL", ZeroPage, 5),
+    (0x07, "SLO", ZeroPage, 5),
+    (0x08, "PHP", Implied, 3),
+    (0x09, "ORA", Immediate, 2),
+    (0x0A, "ASL", Accumulator, 2),
+    (0x0B, "ANC", Immediate, 2),
+    (0x0C, "NOP", Absolute, 4),
+    (0x0D, "ORA", Absolute, 4),
+    (0x0E, "ASL", Absolute, 6),
+    (0x0F, "SLO", Absolute, 6),
+    (0x10, "BPL", Relative, 2),
+    (0x11, "ORA", IndirectY, 5),
+    (0x12, "KIL", Implied, 2),
+    (0x13, "SLO", IndirectY, 8),
+    (0x14, "NOP", ZeroPageX, 4),
+    (0x15, "ORA", ZeroPageX, 4),
+    (0x16, "ASL", ZeroPageX, 6),
+    (0x17, "SLO", ZeroPageX, 6),
+    (0x18, "CLC", Implied, 2),
+    (0x19, "ORA", AbsoluteY, 4),
+    (0x1A, "NOP", Implied, 2),
+    (0x1B, "SLO", AbsoluteY, 7),
+    (0x1C, "NOP", AbsoluteX, 4),
+    (0x1D, "ORA", AbsoluteX, 4),
+    (0x1E, "ASL", AbsoluteX, 7),
+    (0x1F, "SLO", AbsoluteX, 7),
+    (0x20, "JSR", Absolute, 6),
+    (0x21, "AND", IndirectX, 6),
+    (0x22, "KIL", Implied, 2),
+    (0x23, "RLA", IndirectX, 8),
+    (0x24, "BIT", ZeroPage, 3),
+    (0x25, "AND", ZeroPage, 3),
+    (0x26, "ROL", ZeroPage, 5),
+    (0x27, "RLA", ZeroPage, 5),
+    (0x28, "PLP", Implied, 4),
+    (0x29, "AND", Immediate, 2),
+    (0x2A, "ROL", Accumulator, 2),
+    (0x2B, "ANC", Immediate, 2),
+    (0x2C, "BIT", Absolute, 4),
+    (0x2D, "AND", Absolute, 4),
+    (0x2E, "ROL", Absolute, 6),
+    (0x2F, "RLA", Absolute, 6),
+    (0x30, "BMI", Relative, 2),
+    (0x31, "AND", IndirectY, 5),
+    (0x32, "KIL", Implied, 2),
+    (0x33, "RLA", IndirectY, 8),
+    (0x34, "NOP", ZeroPageX, 4),
+    (0x35, "AND", ZeroPageX, 4),
+    (0x36, "ROL", ZeroPageX, 6),
+    (0x37, "RLA", ZeroPageX, 6),
+    (0x38, "SEC", Implied, 2),
+    (0x39, "AND", AbsoluteY, 4),
+    (0x3A, "NOP", Implied, 2),
+    (0x3B, "RLA", AbsoluteY, 7),
+    (0x3C, "NOP", AbsoluteX, 4),
+    (0x3D, "AND", AbsoluteX, 4),
+    (0x3E, "ROL", AbsoluteX, 7),
+    (0x3F, "RLA", AbsoluteX, 7),
+    (0x40, "RTI", Implied, 6),
+    (0x41, "EOR", IndirectX, 6),
+    (0x42, "KIL", Implied, 2),
+    (0x43, "SRE", IndirectX, 8),
+    (0x44, "NOP", ZeroPage, 3),
+    (0x45, "EOR", ZeroPage, 3),
+    (0x46, "LSR", ZeroPage, 5),
+    (0x47, "SRE", ZeroPage, 5),
+    (0x48, "PHA", Implied, 3),
+    (0x49, "EOR", Immediate, 2),
+    (0x4A, "LSR", Accumulator, 2),
+    (0x4B, "ALR", Immediate, 2),
+    (0x4C, "JMP", Absolute, 3),
+    (0x4D, "EOR", Absolute, 4),
+    (0x4E, "LSR", Absolute, 6),
+    (0x4F, "SRE", Absolute, 6),
+    (0x50, "BVC", Relative, 2),
+    (0x51, "EOR", IndirectY, 5),
+    (0x52, "KIL", Implied, 2),
+    (0x53, "SRE", IndirectY, 8),
+    (0x54, "NOP", ZeroPageX, 4),
+    (0x55, "EOR", ZeroPageX, 4),
+    (0x56, "LSR", ZeroPageX, 6),
+    (0x57, "SRE", ZeroPageX, 6),
+    (0x58, "CLI", Implied, 2),
+    (0x59, "EOR", AbsoluteY, 4),
+    (0x5A, "NOP", Implied, 2),
+    (0x5B, "SRE", AbsoluteY, 7),
+    (0x5C, "NOP", AbsoluteX, 4),
+    (0x5D, "EOR", AbsoluteX, 4),
+    (0x5E, "LSR", AbsoluteX, 7),
+    (0x5F, "SRE", AbsoluteX, 7),
+    (0x60, "RTS", Implied, 6),
+    (0x61, "ADC", IndirectX, 6),
+    (0x62, "KIL", Implied, 2),
+    (0x63, "RRA", IndirectX, 8),
+    (0x64, "NOP", ZeroPage, 3),
+    (0x65, "ADC", ZeroPage, 3),
+    (0x66, "ROR", ZeroPage, 5),
+    (0x67, "RRA", ZeroPage, 5),
+    (0x68, "PLA", Implied, 4),
+    (0x69, "ADC", Immediate, 2),
+    (0x6A, "ROR", Accumulator, 2),
+    (0x6B, "ARR", Immediate, 2),
+    (0x6C, "JMP", Indirect, 5),
+    (0x6D, "ADC", Absolute, 4),
+    (0x6E, "ROR", Absolute, 6),
+    (0x6F, "RRA", Absolute, 6),
+    (0x70, "BVS", Relative, 2),
+    (0x71, "ADC", IndirectY, 5),
+    (0x72, "KIL", Implied, 2),
+    (0x73, "RRA", IndirectY, 8),
+    (0x74, "NOP", ZeroPageX, 4),
+    (0x75, "ADC", ZeroPageX, 4),
+    (0x76, "ROR", ZeroPageX, 6),
+    (0x77, "RRA", ZeroPageX, 6),
+    (0x78, "SEI", Implied, 2),
+    (0x79, "ADC", AbsoluteY, 4),
+    (0x7A, "NOP", Implied, 2),
+    (0x7B, "RRA", AbsoluteY, 7),
+    (0x7C, "NOP", AbsoluteX, 4),
+    (0x7D, "ADC", AbsoluteX, 4),
+    (0x7E, "ROR", AbsoluteX, 7),
+    (0x7F, "RRA", AbsoluteX, 7),
+    (0x80, "NOP", Immediate, 2),
+    (0x81, "STA", IndirectX, 6),
+    (0x82, "NOP", Immediate, 2),
+    (0x83, "SAX", IndirectX, 6),
+    (0x84, "STY", ZeroPage, 3),
+    (0x85, "STA", ZeroPage, 3),
+    (0x86, "STX", ZeroPage, 3),
+    (0x87, "SAX", ZeroPage, 3),
+    (0x88, "DEY", Implied, 2),
+    (0x89, "NOP", Immediate, 2),
+    (0x8A, "TXA", Implied, 2),
+    (0x8B, "XAA", Immediate, 2),
+    (0x8C, "STY", Absolute, 4),
+    (0x8D, "STA", Absolute, 4),
+    (0x8E, "STX", Absolute, 4),
+    (0x8F, "SAX", Absolute, 4),
+    (0x90, "BCC", Relative, 2),
+    (0x91, "STA", IndirectY, 6),
+    (0x92, "KIL", Implied, 2),
+    (0x93, "AHX", IndirectY, 6),
+    (0x94, "STY", ZeroPageX, 4),
+    (0x95, "STA", ZeroPageX, 4),
+    (0x96, "STX", ZeroPageY, 4),
+    (0x97, "SAX", ZeroPageY, 4),
+    (0x98, "TYA", Implied, 2),
+    (0x99, "STA", AbsoluteY, 5),
+    (0x9A, "TXS", Implied, 2),
+    (0x9B, "TAS", AbsoluteY, 5),
+    (0x9C, "SHY", AbsoluteX, 5),
+    (0x9D, "STA", AbsoluteX, 5),
+    (0x9E, "SHX", AbsoluteY, 5),
+    (0x9F, "AHX", AbsoluteY, 5),
+    (0xA0, "LDY", Immediate, 2),
+    (0xA1, "LDA", IndirectX, 6),
+    (0xA2, "LDX", Immediate, 2),
+    (0xA3, "LAX", IndirectX, 6),
+    (0xA4, "LDY", ZeroPage, 3),
+    (0xA5, "LDA", ZeroPage, 3),
+    (0xA6, "LDX", ZeroPage, 3),
+    (0xA7, "LAX", ZeroPage, 3),
+    (0xA8, "TAY", Implied, 2),
+    (0xA9, "LDA", Immediate, 2),
+    (0xAA, "TAX", Implied, 2),
+    (0xAB, "LAX", Immediate, 2),
+    (0xAC, "LDY", Absolute, 4),
+    (0xAD, "LDA", Absolute, 4),
+    (0xAE, "LDX", Absolute, 4),
+    (0xAF, "LAX", Absolute, 4),
+    (0xB0, "BCS", Relative, 2),
+    (0xB1, "LDA", IndirectY, 5),
+    (0xB2, "KIL", Implied, 2),
+    (0xB3, "LAX", IndirectY, 5),
+    (0xB4, "LDY", ZeroPageX, 4),
+    (0xB5, "LDA", ZeroPageX, 4),
+    (0xB6, "LDX", ZeroPageY, 4),
+    (0xB7, "LAX", ZeroPageY, 4),
+    (0xB8, "CLV", Implied, 2),
+    (0xB9, "LDA", AbsoluteY, 4),
+    (0xBA, "TSX", Implied, 2),
+    (0xBB, "LAS", AbsoluteY, 4),
+    (0xBC, "LDY", AbsoluteX, 4),
+    (0xBD, "LDA", AbsoluteX, 4),
+    (0xBE, "LDX", AbsoluteY, 4),
+    (0xBF, "LAX", AbsoluteY, 4),
+    (0xC0, "CPY", Immediate, 2),
+    (0xC1, "CMP", IndirectX, 6),
+    (0xC2, "NOP", Immediate, 2),
+    (0xC3, "DCP", IndirectX, 8),
+    (0xC4, "CPY", ZeroPage, 3),
+    (0xC5, "CMP", ZeroPage, 3),
+    (0xC6, "DEC", ZeroPage, 5),
+    (0xC7, "DCP", ZeroPage, 5),
+    (0xC8, "INY", Implied, 2),
+    (0xC9, "CMP", Immediate, 2),
+    (0xCA, "DEX", Implied, 2),
+    (0xCB, "AXS", Immediate, 2),
+    (0xCC, "CPY", Absolute, 4),
+    (0xCD, "CMP", Absolute, 4),
+    (0xCE, "DEC", Absolute, 6),
+    (0xCF, "DCP", Absolute, 6),
+    (0xD0, "BNE", Relative, 2),
+    (0xD1, "CMP", IndirectY, 5),
+    (0xD2, "KIL", Implied, 2),
+    (0xD3, "DCP", IndirectY, 8),
+    (0xD4, "NOP", ZeroPageX, 4),
+    (0xD5, "CMP", ZeroPageX, 4),
+    (0xD6, "DEC", ZeroPageX, 6),
+    (0xD7, "DCP", ZeroPageX, 6),
+    (0xD8, "CLD", Implied, 2),
+    (0xD9, "CMP", AbsoluteY, 4),
+    (0xDA, "NOP", Implied, 2),
+    (0xDB, "DCP", AbsoluteY, 7),
+    (0xDC, "NOP", AbsoluteX, 4),
+    (0xDD, "CMP", AbsoluteX, 4),
+    (0xDE, "DEC", AbsoluteX, 7),
+    (0xDF, "DCP", AbsoluteX, 7),
+    (0xE0, "CPX", Immediate, 2),
+    (0xE1, "SBC", IndirectX, 6),
+    (0xE2, "NOP", Immediate, 2),
+    (0xE3, "ISB", IndirectX, 8),
+    (0xE4, "CPX", ZeroPage, 3),
+    (0xE5, "SBC", ZeroPage, 3),
+    (0xE6, "INC", ZeroPage, 5),
+    (0xE7, "ISB", ZeroPage, 5),
+    (0xE8, "INX", Implied, 2),
+    (0xE9, "SBC", Immediate, 2),
+    (0xEA, "NOP", Implied, 2),
+    (0xEB, "SBC", Immediate, 2),
+    (0xEC, "CPX", Absolute, 4),
+    (0xED, "SBC", Absolute, 4),
+    (0xEE, "INC", Absolute, 6),
+    (0xEF, "ISB", Absolute, 6),
+    (0xF0, "BEQ", Relative, 2),
+    (0xF1, "SBC", IndirectY, 5),
+    (0xF2, "KIL", Implied, 2),
+    (0xF3, "ISB", IndirectY, 8),
+    (0xF4, "NOP", ZeroPageX, 4),
+    (0xF5, "SBC", ZeroPageX, 4),
+    (0xF6, "INC", ZeroPageX, 6),
+    (0xF7, "ISB", ZeroPageX, 6),
+    (0xF8, "SED", Implied, 2),
+    (0xF9, "SBC", AbsoluteY, 4),
+    (0xFA, "NOP", Implied, 2),
+    (0xFB, "ISB", AbsoluteY, 7),
+    (0xFC, "NOP", AbsoluteX, 4),
+    (0xFD, "SBC", AbsoluteX, 4),
+    (0xFE, "INC", AbsoluteX, 7),
+    (0xFF, "ISB", AbsoluteX, 7),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::apu::Apu;
+    use nes::joypad::Joypad;
+    use nes::mapper::{self, Mapper};
+    use nes::ppu::Ppu;
+
+    const PROGRAM_ADDR: u16 = 0x0200;
+    // IRQ vector of mapper::test_rom, last 1KB of PRG is filled with 0x3F
+    const TEST_ROM_IRQ_VECTOR: u16 = 0x3F3F;
+
+    fn new_cpu() -> Cpu {
+        new_cpu_with_mapper(mapper::empty()).0
+    }
+
+    type IrqSources = (Rc<RefCell<Box<dyn Mapper>>>, Rc<RefCell<Box<Apu>>>);
+
+    // also returns the mapper and APU, to drive the IRQ sources
+    fn new_cpu_with_mapper(mapper: Box<dyn Mapper>) -> (Cpu, IrqSources) {
+        let mapper = Rc::new(RefCell::new(mapper));
+        let ppu = Rc::new(RefCell::new(Box::new(Ppu::new(mapper.clone()))));
+        let apu = Rc::new(RefCell::new(Box::new(Apu::new())));
+        let joypad = Rc::new(RefCell::new(Box::new(Joypad::new())));
+        let mbc = Mbc::new(mapper.clone(), ppu, apu.clone(), joypad);
+        let mbc = Rc::new(RefCell::new(Box::new(mbc)));
+        let mut cpu = Cpu::new(mbc);
+        cpu.pc = PROGRAM_ADDR;
+        (cpu, (mapper, apu))
+    }
+
+    // CPU on an MMC3 board with interrupts enabled
+    fn new_cpu_with_irq_sources() -> (Cpu, IrqSources) {
+        let rom = mapper::test_rom(4, 0x10000, 0x2000);
+        let (mut cpu, sources) = new_cpu_with_mapper(mapper::new_mapper(rom));
+        cpu.set_flag(FLAG_IRQ, false);
+        (cpu, sources)
+    }
+
+    // MMC3 counter reloads to 0 on an A12 rise and asserts IRQ
+    fn raise_mapper_irq(mapper: &Rc<RefCell<Box<dyn Mapper>>>) {
+        let mut mapper = mapper.borrow_mut();
+        mapper.write_prg(0xC000, 0x00);
+        mapper.write_prg(0xC001, 0x00);
+        mapper.write_prg(0xE001, 0x00);
+        mapper.ppu_address(0x0000, 0);
+        mapper.ppu_address(0x1000, 100);
+    }
+
+    // DMC finishes a 1 byte sample with IRQ enabled
+    fn raise_apu_irq(cpu: &Cpu, apu: &Rc<RefCell<Box<Apu>>>) {
+        write(cpu, 0x4010, 0x80);
+        write(cpu, 0x4013, 0x00);
+        write(cpu, 0x4015, 0x10);
+        apu.borrow_mut().tick();
+    }
+
+    fn is_irq_asserted(cpu: &Cpu) -> bool {
+        cpu.mbc.borrow().is_raise_irq()
+    }
+
+    fn write(cpu: &Cpu, addr: u16, data: u8) {
+        cpu.mbc.borrow_mut().write(addr, data);
+    }
+
+    // place one instruction at PROGRAM_ADDR and execute it, returns spent cycles
+    fn execute(cpu: &mut Cpu, program: &[u8]) -> u64 {
+        for (i, byte) in program.iter().enumerate() {
+            write(cpu, PROGRAM_ADDR + i as u16, *byte);
+        }
+        cpu.pc = PROGRAM_ADDR;
+        let before = cpu.cycle;
+        cpu.tick();
+        assert_eq!(cpu.pc, PROGRAM_ADDR + program.len() as u16);
+        cpu.cycle - before
+    }
+
+    #[test]
+    fn lax_loads_a_and_x() {
+        let mut cpu = new_cpu();
+        write(&cpu, 0x0010, 0x80);
+        assert_eq!(execute(&mut cpu, &[0xA7, 0x10]), 3);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.x, 0x80);
+        assert!(cpu.get_flag(FLAG_NEG));
+        assert!(!cpu.get_flag(FLAG_ZER));
+    }
+
+    #[test]
+    fn lax_absolute_y_page_cross_cycle() {
+        let mut cpu = new_cpu();
+        cpu.y = 0x01;
+        write(&cpu, 0x0100, 0x00);
+        assert_eq!(execute(&mut cpu, &[0xBF, 0xFF, 0x00]), 5);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.get_flag(FLAG_ZER));
+    }
+
+    #[test]
+    fn sax_stores_a_and_x() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xF0;
+        cpu.x = 0x3C;
+        cpu.p = FLAG_ZER;
+        assert_eq!(execute(&mut cpu, &[0x87, 0x10]), 3);
+        assert_eq!(cpu.read(0x0010), 0x30);
+        assert_eq!(cpu.p, FLAG_ZER);
+    }
+
+    #[test]
+    fn dcp_decrements_and_compares() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x40;
+        write(&cpu, 0x0010, 0x41);
+        assert_eq!(execute(&mut cpu, &[0xC7, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x40);
+        assert!(cpu.get_flag(FLAG_ZER));
+        assert!(cpu.get_flag(FLAG_CRY));
+        assert_eq!(cpu.a, 0x40);
+    }
+
+    #[test]
+    fn isc_increments_and_subtracts() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x10;
+        cpu.p = FLAG_CRY;
+        write(&cpu, 0x0010, 0x0F);
+        assert_eq!(execute(&mut cpu, &[0xE7, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x10);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.get_flag(FLAG_ZER));
+        assert!(cpu.get_flag(FLAG_CRY));
+    }
+
+    #[test]
+    fn slo_shifts_and_ors() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x01;
+        write(&cpu, 0x0010, 0x81);
+        assert_eq!(execute(&mut cpu, &[0x07, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x02);
+        assert_eq!(cpu.a, 0x03);
+        assert!(cpu.get_flag(FLAG_CRY));
+    }
+
+    #[test]
+    fn rla_rotates_and_ands() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xFF;
+        cpu.p = FLAG_CRY;
+        write(&cpu, 0x0010, 0x40);
+        assert_eq!(execute(&mut cpu, &[0x27, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x81);
+        assert_eq!(cpu.a, 0x81);
+        assert!(!cpu.get_flag(FLAG_CRY));
+        assert!(cpu.get_flag(FLAG_NEG));
+    }
+
+    #[test]
+    fn sre_shifts_and_eors() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x0F;
+        write(&cpu, 0x0010, 0x03);
+        assert_eq!(execute(&mut cpu, &[0x47, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x01);
+        assert_eq!(cpu.a, 0x0E);
+        assert!(cpu.get_flag(FLAG_CRY));
+    }
+
+    #[test]
+    fn rra_rotates_and_adds() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x10;
+        write(&cpu, 0x0010, 0x03);
+        assert_eq!(execute(&mut cpu, &[0x67, 0x10]), 5);
+        assert_eq!(cpu.read(0x0010), 0x01);
+        // carry from the rotate is added
+        assert_eq!(cpu.a, 0x12);
+        assert!(!cpu.get_flag(FLAG_CRY));
+    }
+
+    #[test]
+    fn anc_copies_negative_to_carry() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xF0;
+        assert_eq!(execute(&mut cpu, &[0x0B, 0x80]), 2);
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.get_flag(FLAG_CRY));
+        assert!(cpu.get_flag(FLAG_NEG));
+    }
+
+    #[test]
+    fn alr_ands_and_shifts() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xFF;
+        assert_eq!(execute(&mut cpu, &[0x4B, 0x03]), 2);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.get_flag(FLAG_CRY));
+    }
+
+    #[test]
+    fn arr_sets_carry_and_overflow() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xFF;
+        cpu.p = FLAG_CRY;
+        assert_eq!(execute(&mut cpu, &[0x6B, 0x80]), 2);
+        assert_eq!(cpu.a, 0xC0);
+        assert!(cpu.get_flag(FLAG_CRY));
+        assert!(cpu.get_flag(FLAG_OVF));
+        assert!(cpu.get_flag(FLAG_NEG));
+
+        cpu.a = 0xFF;
+        cpu.p = 0;
+        execute(&mut cpu, &[0x6B, 0xC0]);
+        assert_eq!(cpu.a, 0x60);
+        assert!(cpu.get_flag(FLAG_CRY));
+        assert!(!cpu.get_flag(FLAG_OVF));
+    }
+
+    #[test]
+    fn axs_subtracts_from_a_and_x() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x0F;
+        cpu.x = 0xFC;
+        assert_eq!(execute(&mut cpu, &[0xCB, 0x02]), 2);
+        assert_eq!(cpu.x, 0x0A);
+        assert_eq!(cpu.a, 0x0F);
+        assert!(cpu.get_flag(FLAG_CRY));
+
+        cpu.a = 0x01;
+        cpu.x = 0x01;
+        execute(&mut cpu, &[0xCB, 0x02]);
+        assert_eq!(cpu.x, 0xFF);
+        assert!(!cpu.get_flag(FLAG_CRY));
+        assert!(cpu.get_flag(FLAG_NEG));
+    }
+
+    #[test]
+    fn xaa_ands_x_and_immediate() {
+        let mut cpu = new_cpu();
+        cpu.a = 0x00;
+        cpu.x = 0xFF;
+        assert_eq!(execute(&mut cpu, &[0x8B, 0x0F]), 2);
+        assert_eq!(cpu.a, 0x0E);
+    }
+
+    #[test]
+    fn ahx_stores_a_and_x_and_high_byte() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xFF;
+        cpu.x = 0xFF;
+        cpu.y = 0x01;
+        assert_eq!(execute(&mut cpu, &[0x9F, 0x10, 0x03]), 5);
+        assert_eq!(cpu.read(0x0311), 0x04);
+
+        write(&cpu, 0x0020, 0x10);
+        write(&cpu, 0x0021, 0x03);
+        assert_eq!(execute(&mut cpu, &[0x93, 0x20]), 6);
+        assert_eq!(cpu.read(0x0311), 0x04);
+    }
+
+    #[test]
+    fn tas_sets_stack_pointer() {
+        let mut cpu = new_cpu();
+        cpu.a = 0xF3;
+        cpu.x = 0x3F;
+        cpu.y = 0x00;
+        assert_eq!(execute(&mut cpu, &[0x9B, 0x00, 0x04]), 5);
+        assert_eq!(cpu.s, 0x33);
+        assert_eq!(cpu.read(0x0400), 0x01);
+    }
+
+    #[test]
+    fn shx_stores_x_and_high_byte() {
+        let mut cpu = new_cpu();
+        cpu.x = 0xFF;
+        cpu.y = 0x02;
+        assert_eq!(execute(&mut cpu, &[0x9E, 0x00, 0x05]), 5);
+        assert_eq!(cpu.read(0x0502), 0x06);
+    }
+
+    #[test]
+    fn shx_page_cross_corrupts_high_byte() {
+        let mut cpu = new_cpu();
+        cpu.x = 0x03;
+        cpu.y = 0x01;
+        execute(&mut cpu, &[0x9E, 0xFF, 0x04]);
+        // value = 0x03 & 0x05 = 0x01, address high byte replaced with it
+        assert_eq!(cpu.read(0x0100), 0x01);
+    }
+
+    #[test]
+    fn shy_stores_y_and_high_byte() {
+        let mut cpu = new_cpu();
+        cpu.x = 0x02;
+        cpu.y = 0xFF;
+        assert_eq!(execute(&mut cpu, &[0x9C, 0x00, 0x05]), 5);
+        assert_eq!(cpu.read(0x0502), 0x06);
+    }
+
+    #[test]
+    fn las_ands_stack_pointer() {
+        let mut cpu = new_cpu();
+        cpu.s = 0xF0;
+        cpu.y = 0x00;
+        write(&cpu, 0x0300, 0x3C);
+        assert_eq!(execute(&mut cpu, &[0xBB, 0x00, 0x03]), 4);
+        assert_eq!(cpu.a, 0x30);
+        assert_eq!(cpu.x, 0x30);
+        assert_eq!(cpu.s, 0x30);
+    }
+
+    #[test]
+    fn plp_ignores_break_flag() {
+        let mut cpu = new_cpu();
+        write(&cpu, 0x01FF, 0xFF);
+        cpu.s = 0xFE;
+        assert_eq!(execute(&mut cpu, &[0x28]), 4);
+        assert_eq!(cpu.p, !FLAG_BRK);
+    }
+
+    #[test]
+    fn irq_line_is_held_while_any_source_asserts() {
+        let (cpu, (mapper, apu)) = new_cpu_with_irq_sources();
+        assert!(!is_irq_asserted(&cpu));
+
+        raise_mapper_irq(&mapper);
+        raise_apu_irq(&cpu, &apu);
+        assert!(is_irq_asserted(&cpu));
+
+        mapper.borrow_mut().write_prg(0xE000, 0x00);
+        assert!(is_irq_asserted(&cpu), "APU still holds the line");
+
+        raise_mapper_irq(&mapper);
+        write(&cpu, 0x4015, 0x00);
+        assert!(is_irq_asserted(&cpu), "mapper still holds the line");
+
+        mapper.borrow_mut().write_prg(0xE000, 0x00);
+        assert!(!is_irq_asserted(&cpu));
+    }
+
+    #[test]
+    fn irq_is_taken_again_until_acknowledged() {
+        let (mut cpu, (mapper, _)) = new_cpu_with_irq_sources();
+        raise_mapper_irq(&mapper);
+
+        cpu.tick();
+        assert_eq!(cpu.pc, TEST_ROM_IRQ_VECTOR);
+        assert!(cpu.get_flag(FLAG_IRQ));
+        // pushed status has B clear
+        assert_eq!(cpu.read(0x0100 | (cpu.s.wrapping_add(1) as u16)) & FLAG_BRK, 0);
+
+        // not taken while I is set, taken again once it is cleared
+        cpu.pc = PROGRAM_ADDR;
+        write(&cpu, PROGRAM_ADDR, 0x58); // CLI
+        write(&cpu, PROGRAM_ADDR + 1, 0xEA);
+        cpu.tick();
+        cpu.tick();
+        cpu.tick();
+        assert_eq!(cpu.pc, TEST_ROM_IRQ_VECTOR);
+    }
+
+    #[test]
+    fn cli_takes_effect_after_next_instruction() {
+        let (mut cpu, (_, apu)) = new_cpu_with_irq_sources();
+        cpu.set_flag(FLAG_IRQ, true);
+        cpu.irq_masked = true;
+        raise_apu_irq(&cpu, &apu);
+        for (i, byte) in [0x58, 0xEA, 0xEA].iter().enumerate() {
+            write(&cpu, PROGRAM_ADDR + i as u16, *byte);
+        }
+
+        cpu.tick(); // CLI
+        assert_eq!(cpu.pc, PROGRAM_ADDR + 1);
+        cpu.tick(); // NOP still runs
+        assert_eq!(cpu.pc, PROGRAM_ADDR + 2);
+        cpu.tick();
+        assert_eq!(cpu.pc, TEST_ROM_IRQ_VECTOR);
+    }
+
+    #[test]
+    fn irq_is_taken_right_after_sei() {
+        let (mut cpu, (mapper, _)) = new_cpu_with_irq_sources();
+        write(&cpu, PROGRAM_ADDR, 0x78); // SEI
+        write(&cpu, PROGRAM_ADDR + 1, 0xEA);
+
+        cpu.tick();
+        assert!(cpu.get_flag(FLAG_IRQ));
+        raise_mapper_irq(&mapper);
+        cpu.tick();
+        assert_eq!(cpu.pc, TEST_ROM_IRQ_VECTOR);
+
+        // after the interrupt, I blocks it
+        cpu.pc = PROGRAM_ADDR + 1;
+        cpu.tick();
+        assert_eq!(cpu.pc, PROGRAM_ADDR + 2);
+    }
+
+    #[test]
+    fn plp_changes_irq_mask_after_next_instruction() {
+        let (mut cpu, (_, apu)) = new_cpu_with_irq_sources();
+        cpu.set_flag(FLAG_IRQ, true);
+        cpu.irq_masked = true;
+        raise_apu_irq(&cpu, &apu);
+        write(&cpu, 0x01FF, 0x00);
+        cpu.s = 0xFE;
+        for (i, byte) in [0x28, 0xEA, 0xEA].iter().enumerate() {
+            write(&cpu, PROGRAM_ADDR + i as u16, *byte);
+        }
+
+        cpu.tick(); // PLP clears I
+        assert!(!cpu.get_flag(FLAG_IRQ));
+        cpu.tick();
+        assert_eq!(cpu.pc, PROGRAM_ADDR + 2);
+        cpu.tick();
+        assert_eq!(cpu.pc, TEST_ROM_IRQ_VECTOR);
+    }
+
+    #[test]
+    fn implied_nop_is_one_byte() {
+        let mut cpu = new_cpu();
+        assert_eq!(execute(&mut cpu, &[0x1A]), 2);
+    }
+
+    #[test]
+    fn absolute_x_nop_page_cross_cycle() {
+        let mut cpu = new_cpu();
+        cpu.x = 0x01;
+        assert_eq!(execute(&mut cpu, &[0x1C, 0xFF, 0x00]), 5);
+    }
+
+    // place one instruction at PROGRAM_ADDR and execute it, returns (spent cycles, pc)
+    fn execute_jump(cpu: &mut Cpu, program: &[u8]) -> (u64, u16) {
+        for (i, byte) in program.iter().enumerate() {
+            write(cpu, PROGRAM_ADDR + i as u16, *byte);
+        }
+        cpu.pc = PROGRAM_ADDR;
+        let before = cpu.cycle;
+        cpu.tick();
+        (cpu.cycle - before, cpu.pc)
+    }
+
+    #[test]
+    fn taken_branch_adds_cycles() {
+        let mut cpu = new_cpu();
+        // BNE +4, not taken
+        cpu.p = FLAG_ZER;
+        assert_eq!(execute_jump(&mut cpu, &[0xD0, 0x04]), (2, 0x0202));
+        cpu.p = 0;
+        assert_eq!(execute_jump(&mut cpu, &[0xD0, 0x04]), (3, 0x0206));
+        // BNE -16 crosses to $01xx
+        assert_eq!(execute_jump(&mut cpu, &[0xD0, 0xF0]), (4, 0x01F2));
+    }
+
+    #[test]
+    fn jmp_indirect_wraps_in_page() {
+        let mut cpu = new_cpu();
+        write(&cpu, 0x04FF, 0x34);
+        write(&cpu, 0x0400, 0x12);
+        write(&cpu, 0x0500, 0x56);
+        assert_eq!(execute_jump(&mut cpu, &[0x6C, 0xFF, 0x04]), (5, 0x1234));
+    }
+
+    // branches are covered by taken_branch_adds_cycles, KIL stops the CPU
+    #[test]
+    fn opcode_table_matches_dispatch() {
+        for &(opcode, mnemonic, mode, cycles) in OPCODES.iter() {
+            if mode == Relative || mnemonic == "KIL" {
+                continue;
+            }
+            // BRK reads the IRQ vector from PRG ROM
+            let rom = mapper::test_rom(0, 0x4000, 0x2000);
+            let mut cpu = new_cpu_with_mapper(mapper::new_mapper(rom)).0;
+            write(&cpu, PROGRAM_ADDR, opcode);
+            cpu.pc = PROGRAM_ADDR + 1;
+            cpu.process_opcode(opcode);
+            assert_eq!(cpu.cycle, cycles as u64, "opcode:{:02X}", opcode);
+            // ISB is implemented as isc
+            let action = if cpu.last_action == "isc" { "isb" } else { cpu.last_action };
+            assert_eq!(action.to_uppercase(), mnemonic, "opcode:{:02X}", opcode);
+            match mnemonic {
+                "BRK" | "JMP" | "JSR" | "RTI" | "RTS" => {}
+                _ => assert_eq!(cpu.pc, PROGRAM_ADDR + 1 + mode.operand_length(), "opcode:{:02X}", opcode),
+            }
+        }
+    }
+
+    #[test]
+    fn jsr_keeps_decimal_flag() {
+        let mut cpu = new_cpu();
+        cpu.p = FLAG_RSV;
+        assert_eq!(execute_jump(&mut cpu, &[0x20, 0x00, 0x03]), (6, 0x0300));
+        assert_eq!(cpu.p, FLAG_RSV);
+    }
+
+    #[test]
+    fn reset_sets_stack_pointer_and_spends_7_cycles() {
+        let rom = mapper::test_rom(0, 0x4000, 0x2000);
+        let (mut cpu, _) = new_cpu_with_mapper(mapper::new_mapper(rom));
+        cpu.s = 0x00;
+        cpu.reset();
+        assert_eq!(cpu.s, 0xFD);
+        assert_eq!(cpu.cycle, 7);
+        assert_eq!(cpu.pc, 0x0F0F); // test_rom fills the last 1KB of 16KB PRG with 0x0F
+        assert!(cpu.get_flag(FLAG_IRQ));
+    }
+
+    #[test]
+    fn setup_keeps_only_reserved_flag() {
+        let rom = mapper::test_rom(0, 0x4000, 0x2000);
+        let (mut cpu, _) = new_cpu_with_mapper(mapper::new_mapper(rom));
+        cpu.p = 0xFF;
+        cpu.setup();
+        assert_eq!(cpu.p, FLAG_RSV);
+    }
+
+    #[test]
+    fn trace_lines_are_nestest_format() {
+        let mut cpu = new_cpu();
+        cpu.set_trace(true);
+        cpu.x = 0x01;
+        cpu.p = 0x24;
+        cpu.s = 0xFD;
+        write(&cpu, 0x0301, 0x5A);
+        execute(&mut cpu, &[0xBD, 0x00, 0x03]);
+        write(&cpu, 0x0010, 0x00);
+        write(&cpu, 0x0011, 0x03);
+        execute(&mut cpu, &[0xB1, 0x10]);
+        execute(&mut cpu, &[0x04, 0x11]);
+        let mut lines = vec![];
+        cpu.take_trace(&mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                "0200  BD 00 03  LDA $0300,X @ 0301 = 5A         A:00 X:01 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+                "0200  B1 10     LDA ($10),Y = 0300 @ 0300 = 00  A:5A X:01 Y:00 P:24 SP:FD PPU:  0,  0 CYC:4",
+                "0200  04 11    *NOP $11 = 03                    A:00 X:01 Y:00 P:26 SP:FD PPU:  0,  0 CYC:9",
+            ]
+        );
+    }
+}
@@ -0,0 +1,30 @@
+// Nintendulator/nestest style trace line, e.g.
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+use nes::cpu::Cpu;
+use nes::disasm::{self, Instruction};
+
+// ppu_position: (line, dot), pre-render line is printed as 261
+pub fn format(cpu: &Cpu, ppu_position: (i16, i16)) -> String {
+    let instruction = Instruction::decode(cpu.pc, |addr| peek(cpu, addr));
+    let text = instruction.format_resolved(cpu.x, cpu.y, |addr| peek(cpu, addr), None);
+    let (line, dot) = ppu_position;
+    let line = if line < 0 { line + 262 } else { line };
+
+    format!(
+        "{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        disasm::format_line(&instruction, &text),
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.p,
+        cpu.s,
+        line,
+        dot,
+        cpu.cycle
+    )
+}
+
+// I/O registers are shown as FF(open bus) to keep tracing free of side effects
+fn peek(cpu: &Cpu, addr: u16) -> u8 {
+    cpu.mbc.borrow().peek(addr).unwrap_or(0xFF)
+}
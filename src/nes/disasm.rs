@@ -0,0 +1,347 @@
+// 6502 disassembler, decodes the opcode table of the CPU(cpu::OPCODES).
+//
+// memory is read through a closure, so the same code serves trace logs(live memory without side effects),
+// a debugger, and PRG ROM dumps.
+use nes::cpu::OPCODES;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+use self::Mode::*;
+
+impl Mode {
+    // operand bytes
+    pub fn operand_length(&self) -> u16 {
+        match *self {
+            Implied | Accumulator => 0,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+const OFFICIAL_MNEMONICS: [&str; 56] = [
+    "ADC", "AND", "ASL", "BCC", "BCS", "BEQ", "BIT", "BMI", "BNE", "BPL", "BRK", "BVC", "BVS", "CLC",
+    "CLD", "CLI", "CLV", "CMP", "CPX", "CPY", "DEC", "DEX", "DEY", "EOR", "INC", "INX", "INY", "JMP",
+    "JSR", "LDA", "LDX", "LDY", "LSR", "NOP", "ORA", "PHA", "PHP", "PLA", "PLP", "ROL", "ROR", "RTI",
+    "RTS", "SBC", "SEC", "SED", "SEI", "STA", "STX", "STY", "TAX", "TAY", "TSX", "TXA", "TXS", "TYA",
+];
+
+// unofficial opcodes reuse some official mnemonics(NOP, SBC)
+fn is_official(opcode: u8, mnemonic: &str) -> bool {
+    match opcode {
+        0xEA => true,
+        0xEB => false,
+        _ => mnemonic != "NOP" && OFFICIAL_MNEMONICS.contains(&mnemonic),
+    }
+}
+
+// labels for addresses, shown instead of numbers
+pub struct Symbols {
+    labels: HashMap<u16, String>,
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            labels: HashMap::new(),
+        }
+    }
+
+    // one "ADDR NAME" per line, ADDR is hex("$C000" or "C000"), '#' starts a comment
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        for (no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let addr = fields.next().unwrap().trim_start_matches('$');
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| format!("line {}: invalid address", no + 1))?;
+            let name = fields.next().ok_or_else(|| format!("line {}: missing name", no + 1))?;
+            symbols.insert(addr, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_owned());
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|name| name.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    pub is_official: bool,
+    pub operand: u16, // 8 or 16bit, as is in memory
+}
+
+impl Instruction {
+    pub fn decode<F: Fn(u16) -> u8>(addr: u16, read: F) -> Self {
+        let opcode = read(addr);
+        let (_, mnemonic, mode, _) = OPCODES[opcode as usize];
+        let operand = match mode.operand_length() {
+            0 => 0,
+            1 => read(addr.wrapping_add(1)) as u16,
+            _ => read(addr.wrapping_add(1)) as u16 | (read(addr.wrapping_add(2)) as u16) << 8,
+        };
+        Instruction {
+            addr,
+            opcode,
+            mnemonic,
+            mode,
+            is_official: is_official(opcode, mnemonic),
+            operand,
+        }
+    }
+
+    pub fn length(&self) -> u16 {
+        1 + self.mode.operand_length()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode];
+        match self.mode.operand_length() {
+            0 => {}
+            1 => bytes.push(self.operand as u8),
+            _ => {
+                bytes.push(self.operand as u8);
+                bytes.push((self.operand >> 8) as u8);
+            }
+        }
+        bytes
+    }
+
+    fn is_jump(&self) -> bool {
+        self.mnemonic == "JMP" || self.mnemonic == "JSR"
+    }
+
+    // destination known without running: branches, JMP/JSR absolute
+    pub fn target(&self) -> Option<u16> {
+        match self.mode {
+            Relative => Some(self.addr.wrapping_add(2).wrapping_add(self.operand as u8 as i8 as u16)),
+            Absolute if self.is_jump() => Some(self.operand),
+            _ => None,
+        }
+    }
+
+    // e.g. "LDA $0300,X", addresses are replaced by labels when available
+    pub fn format(&self, symbols: Option<&Symbols>) -> String {
+        let operand = self.format_operand(symbols);
+        if operand.is_empty() {
+            self.mnemonic.to_owned()
+        } else {
+            format!("{} {}", self.mnemonic, operand)
+        }
+    }
+
+    fn format_operand(&self, symbols: Option<&Symbols>) -> String {
+        let label = |addr: u16, width: usize| match symbols.and_then(|symbols| symbols.get(addr)) {
+            Some(name) => name.to_owned(),
+            None => format!("${:01$X}", addr, width),
+        };
+        let zero_page = self.operand & 0xFF;
+        match self.mode {
+            Implied => String::new(),
+            Accumulator => "A".to_owned(),
+            Immediate => format!("#${:02X}", self.operand),
+            ZeroPage => label(zero_page, 2),
+            ZeroPageX => format!("{},X", label(zero_page, 2)),
+            ZeroPageY => format!("{},Y", label(zero_page, 2)),
+            Absolute => label(self.operand, 4),
+            AbsoluteX => format!("{},X", label(self.operand, 4)),
+            AbsoluteY => format!("{},Y", label(self.operand, 4)),
+            Indirect => format!("({})", label(self.operand, 4)),
+            IndirectX => format!("({},X)", label(zero_page, 2)),
+            IndirectY => format!("({}),Y", label(zero_page, 2)),
+            Relative => label(self.target().unwrap(), 4),
+        }
+    }
+
+    // with effective address and the value there, as Nintendulator logs. e.g.
+    // "LDA $0300,X @ 0301 = 5A", "LDA ($80,X) @ 80 = 0200 = 5A", "LDA ($89),Y = 0300 @ 0300 = 89"
+    pub fn format_resolved<F: Fn(u16) -> u8>(&self, x: u8, y: u8, read: F, symbols: Option<&Symbols>) -> String {
+        let text = self.format(symbols);
+        // pointer high byte is read from the same page, as the 6502 does
+        let read16 = |addr: u16| {
+            let high_addr = (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF);
+            read(addr) as u16 | (read(high_addr) as u16) << 8
+        };
+        let zero_page = self.operand as u8;
+        match self.mode {
+            ZeroPage => format!("{} = {:02X}", text, read(zero_page as u16)),
+            ZeroPageX | ZeroPageY => {
+                let index = if self.mode == ZeroPageX { x } else { y };
+                let addr = zero_page.wrapping_add(index);
+                format!("{} @ {:02X} = {:02X}", text, addr, read(addr as u16))
+            }
+            Absolute if !self.is_jump() => format!("{} = {:02X}", text, read(self.operand)),
+            AbsoluteX | AbsoluteY => {
+                let index = if self.mode == AbsoluteX { x } else { y };
+                let addr = self.operand.wrapping_add(index as u16);
+                format!("{} @ {:04X} = {:02X}", text, addr, read(addr))
+            }
+            Indirect => format!("{} = {:04X}", text, read16(self.operand)),
+            IndirectX => {
+                let pointer = zero_page.wrapping_add(x);
+                let addr = read16(pointer as u16);
+                format!("{} @ {:02X} = {:04X} = {:02X}", text, pointer, addr, read(addr))
+            }
+            IndirectY => {
+                let base = read16(zero_page as u16);
+                let addr = base.wrapping_add(y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", text, base, addr, read(addr))
+            }
+            _ => text,
+        }
+    }
+}
+
+// listing of code placed at base, e.g. a PRG ROM bank.
+// labeled addresses get a "name:" line, bytes past the end are shown as .db
+pub fn disassemble(data: &[u8], base: u16, symbols: Option<&Symbols>) -> Vec<String> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = base.wrapping_add(offset as u16);
+        if let Some(name) = symbols.and_then(|symbols| symbols.get(addr)) {
+            lines.push(format!("{}:", name));
+        }
+        let instruction = Instruction::decode(addr, |addr| {
+            data.get(addr.wrapping_sub(base) as usize).cloned().unwrap_or(0)
+        });
+        let length = instruction.length() as usize;
+        if offset + length > data.len() {
+            let bytes: Vec<String> = data[offset..].iter().map(|byte| format!("${:02X}", byte)).collect();
+            lines.push(format!("{:04X}  .db {}", addr, bytes.join(", ")));
+            break;
+        }
+        lines.push(format_line(&instruction, &instruction.format(symbols)));
+        offset += length;
+    }
+    lines
+}
+
+// "C000  4C F5 C5  JMP $C5F5", unofficial opcodes are marked with '*'
+pub fn format_line(instruction: &Instruction, text: &str) -> String {
+    let bytes: Vec<String> = instruction.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+    let mark = if instruction.is_official { ' ' } else { '*' };
+    format!("{:04X}  {:<8} {}{}", instruction.addr, bytes.join(" "), mark, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Instruction {
+        Instruction::decode(0xC000, |addr| bytes.get((addr - 0xC000) as usize).cloned().unwrap_or(0))
+    }
+
+    #[test]
+    fn opcode_table_is_in_order() {
+        for (index, &(opcode, _, _, _)) in OPCODES.iter().enumerate() {
+            assert_eq!(index, opcode as usize);
+        }
+        let official = (0..=0xFFu8).filter(|&opcode| decode(&[opcode]).is_official).count();
+        assert_eq!(official, 151);
+    }
+
+    #[test]
+    fn formats_every_addressing_mode() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0x0A], "ASL A"),
+            (&[0xEA], "NOP"),
+            (&[0xA9, 0x10], "LDA #$10"),
+            (&[0xA5, 0x10], "LDA $10"),
+            (&[0xB5, 0x10], "LDA $10,X"),
+            (&[0xB6, 0x10], "LDX $10,Y"),
+            (&[0xAD, 0x34, 0x12], "LDA $1234"),
+            (&[0xBD, 0x34, 0x12], "LDA $1234,X"),
+            (&[0xB9, 0x34, 0x12], "LDA $1234,Y"),
+            (&[0x6C, 0x34, 0x12], "JMP ($1234)"),
+            (&[0xA1, 0x10], "LDA ($10,X)"),
+            (&[0xB1, 0x10], "LDA ($10),Y"),
+            (&[0xD0, 0xFE], "BNE $C000"),
+            (&[0xE7, 0x10], "ISB $10"),
+        ];
+        for &(bytes, text) in cases {
+            let instruction = decode(bytes);
+            assert_eq!(instruction.format(None), text);
+            assert_eq!(instruction.length() as usize, bytes.len());
+            assert_eq!(instruction.bytes(), bytes.to_vec());
+        }
+        assert!(!decode(&[0xE7, 0x10]).is_official);
+    }
+
+    #[test]
+    fn labels_replace_addresses() {
+        let symbols = Symbols::parse("# comment\n$C000 loop\n0010 pointer\n2002 PPUSTATUS\n").unwrap();
+        assert_eq!(decode(&[0xD0, 0xFE]).format(Some(&symbols)), "BNE loop");
+        assert_eq!(decode(&[0xB1, 0x10]).format(Some(&symbols)), "LDA (pointer),Y");
+        assert_eq!(decode(&[0x2C, 0x02, 0x20]).format(Some(&symbols)), "BIT PPUSTATUS");
+        assert!(Symbols::parse("XYZ name").is_err());
+    }
+
+    #[test]
+    fn resolves_effective_address() {
+        let memory = |addr: u16| match addr {
+            0x0010 => 0x00,
+            0x0011 => 0x03,
+            0x0302 => 0x5A,
+            _ => 0xEE,
+        };
+        assert_eq!(
+            decode(&[0xB1, 0x10]).format_resolved(0, 2, memory, None),
+            "LDA ($10),Y = 0300 @ 0302 = 5A"
+        );
+        assert_eq!(
+            decode(&[0xA1, 0x0E]).format_resolved(2, 0, memory, None),
+            "LDA ($0E,X) @ 10 = 0300 = EE"
+        );
+        assert_eq!(decode(&[0x20, 0x00, 0xC1]).format_resolved(0, 0, memory, None), "JSR $C100");
+    }
+
+    #[test]
+    fn listing_of_bank() {
+        let symbols = Symbols::parse("C000 reset").unwrap();
+        let lines = disassemble(&[0x4C, 0x00, 0xC0, 0x04, 0x10, 0xAD, 0x00], 0xC000, Some(&symbols));
+        assert_eq!(
+            lines,
+            vec![
+                "reset:",
+                "C000  4C 00 C0  JMP reset",
+                "C003  04 10    *NOP $10",
+                "C005  .db $AD, $00",
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use nes::state::{StateError, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct Joypad {
     register: u8,
@@ -22,6 +24,12 @@ pub const BUTTON_LEFT: u8 = 0x40;
 #[allow(dead_code)]
 pub const BUTTON_RIGHT: u8 = 0x80;
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
@@ -72,6 +80,19 @@ impl Joypad {
         if self.counter < 0x07 {
             self.counter += 1;
         }
-        return result;
+        result
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u8(self.state);
+        state.write_u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.state = state.read_u8()?;
+        self.counter = state.read_u8()?;
+        Ok(())
     }
 }
@@ -0,0 +1,93 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+// mapper 7
+pub struct Axrom {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+    register: u8, // ---M -PPP (M: single screen page, P: 32KB PRG bank)
+}
+
+impl Axrom {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        Axrom {
+            rom: rom,
+            chr: chr,
+            register: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let banks = cmp::max(self.rom.prg().len() / PRG_BANK_SIZE, 1);
+        let bank = (self.register & 0x07) as usize % banks;
+        self.rom.prg()[bank * PRG_BANK_SIZE + (addr as usize & 0x7FFF)]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        info!("Axrom::write_prg({:04x}, {:02x})", addr, data);
+        self.register = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if (self.register & 0x10) == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.chr.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    #[test]
+    fn switches_32kb_prg_bank() {
+        let mut axrom = Axrom::new(mapper::test_rom(7, 0x20000, 0));
+        assert_eq!(axrom.read_prg(0x8000), 0);
+
+        axrom.write_prg(0x8000, 0x03);
+        assert_eq!(axrom.read_prg(0x8000), 3 * 32);
+        assert_eq!(axrom.read_prg(0xC000), 3 * 32 + 16);
+    }
+
+    #[test]
+    fn bit4_selects_single_screen_page() {
+        let mut axrom = Axrom::new(mapper::test_rom(7, 0x20000, 0));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+        axrom.write_prg(0x8000, 0x11);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+        axrom.write_prg(0x8000, 0x01);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
@@ -0,0 +1,84 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// mapper 3
+pub struct Cnrom {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+    chr_bank: u8, // $0000-$1FFF
+}
+
+impl Cnrom {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        Cnrom {
+            rom: rom,
+            chr: chr,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let banks = cmp::max(self.chr.len() / CHR_BANK_SIZE, 1);
+        (self.chr_bank as usize % banks) * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Mapper for Cnrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.rom.read_prg(addr & 0x7FFF)
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        info!("Cnrom::write_prg({:04x}, {:02x})", addr, data);
+        self.chr_bank = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.chr_bank);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.chr_bank = state.read_u8()?;
+        self.chr.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    #[test]
+    fn switches_8kb_chr_bank() {
+        let mut cnrom = Cnrom::new(mapper::test_rom(3, 0x8000, 0x8000));
+        assert_eq!(cnrom.read_chr(0x0000), 0);
+
+        cnrom.write_prg(0x8000, 0x02);
+        assert_eq!(cnrom.read_chr(0x0000), 2 * 8);
+        assert_eq!(cnrom.read_chr(0x1FFF), 2 * 8 + 7);
+        assert_eq!(cnrom.read_prg(0xC000), 16, "PRG is not switched");
+    }
+}
@@ -0,0 +1,287 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+// SUROM: bit 4 of CHR bank selects 256KB PRG page
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+// mapper 1
+pub struct Mmc1 {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+
+    shift_register: u8, // 5bit serial port
+    shift_count: u8,
+
+    control: u8,   // $8000-$9FFF
+    chr_bank0: u8, // $A000-$BFFF
+    chr_bank1: u8, // $C000-$DFFF
+    prg_bank: u8,  // $E000-$FFFF
+
+    prg_offsets: [usize; 2], // $8000, $C000
+    chr_offsets: [usize; 2], // $0000, $1000
+}
+
+impl Mmc1 {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        let mut mmc1 = Mmc1 {
+            rom: rom,
+            chr: chr,
+            shift_register: 0,
+            shift_count: 0,
+            control: 0x0C, // fix last bank at $C000
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+            prg_offsets: [0, 0],
+            chr_offsets: [0, 0],
+        };
+        mmc1.update_offsets();
+        mmc1
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        info!("Mmc1::write_register({:04x}, {:02x})", addr, data);
+        match addr {
+            0x8000...0x9FFF => self.control = data,
+            0xA000...0xBFFF => self.chr_bank0 = data,
+            0xC000...0xDFFF => self.chr_bank1 = data,
+            0xE000...0xFFFF => self.prg_bank = data,
+            _ => unreachable!(),
+        }
+        self.update_offsets();
+    }
+
+    fn update_offsets(&mut self) {
+        let prg_size = self.rom.prg().len();
+        let outer = if prg_size > PRG_OUTER_BANK_SIZE && (self.chr_bank0 & 0x10) != 0 {
+            PRG_OUTER_BANK_SIZE
+        } else {
+            0
+        };
+        let inner_banks = cmp::max(cmp::min(prg_size, PRG_OUTER_BANK_SIZE) / PRG_BANK_SIZE, 1);
+        let bank = self.prg_bank as usize & 0x0F;
+        let last = inner_banks - 1;
+
+        let (low, high) = match (self.control >> 2) & 0x03 {
+            0 | 1 => {
+                // switch 32KB, ignore low bit
+                let bank = bank & 0x0E;
+                (bank, bank + 1)
+            }
+            2 => (0, bank), // fix first bank at $8000
+            3 => (bank, last), // fix last bank at $C000
+            _ => unreachable!(),
+        };
+        self.prg_offsets = [
+            outer + (low % inner_banks) * PRG_BANK_SIZE,
+            outer + (high % inner_banks) * PRG_BANK_SIZE,
+        ];
+
+        let chr_banks = cmp::max(self.chr.len() / CHR_BANK_SIZE, 1);
+        let (low, high) = if (self.control & 0x10) == 0 {
+            // switch 8KB, ignore low bit
+            let bank = self.chr_bank0 as usize & 0x1E;
+            (bank, bank + 1)
+        } else {
+            (self.chr_bank0 as usize, self.chr_bank1 as usize)
+        };
+        self.chr_offsets = [
+            (low % chr_banks) * CHR_BANK_SIZE,
+            (high % chr_banks) * CHR_BANK_SIZE,
+        ];
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = (addr as usize / CHR_BANK_SIZE) & 0x01;
+        self.chr_offsets[bank] + (addr as usize % CHR_BANK_SIZE)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = ((addr as usize - 0x8000) / PRG_BANK_SIZE) & 0x01;
+        let index = self.prg_offsets[bank] + (addr as usize % PRG_BANK_SIZE);
+        self.rom.prg()[index]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        if (data & 0x80) != 0 {
+            // reset shift register, and fix last bank at $C000
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            self.update_offsets();
+            return;
+        }
+
+        self.shift_register |= (data & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            let value = self.shift_register;
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.write_register(addr, value);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            _ => unreachable!(),
+        }
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.shift_register);
+        state.write_u8(self.shift_count);
+        state.write_u8(self.control);
+        state.write_u8(self.chr_bank0);
+        state.write_u8(self.chr_bank1);
+        state.write_u8(self.prg_bank);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.shift_register = state.read_u8()?;
+        self.shift_count = state.read_u8()?;
+        // the fifth write empties the shift register
+        if self.shift_count > 4 {
+            return Err(StateError::InvalidValue("MMC1 shift count"));
+        }
+        self.control = state.read_u8()?;
+        self.chr_bank0 = state.read_u8()?;
+        self.chr_bank1 = state.read_u8()?;
+        self.prg_bank = state.read_u8()?;
+        self.chr.load_state(state)?;
+        self.update_offsets();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    // 128KB PRG(8 x 16KB), 32KB CHR(8 x 4KB)
+    fn new_mmc1() -> Mmc1 {
+        Mmc1::new(mapper::test_rom(1, 0x20000, 0x8000))
+    }
+
+    // serial write, LSB first
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for i in 0..5 {
+            mmc1.write_prg(addr, (value >> i) & 0x01);
+        }
+    }
+
+    // 16KB bank number at addr
+    fn prg_bank(mmc1: &Mmc1, addr: u16) -> u8 {
+        mmc1.read_prg(addr) / 16
+    }
+
+    // 4KB bank number at addr
+    fn chr_bank(mmc1: &Mmc1, addr: u16) -> u8 {
+        mmc1.read_chr(addr) / 4
+    }
+
+    #[test]
+    fn register_is_written_on_fifth_write() {
+        let mut mmc1 = new_mmc1();
+        assert_eq!(prg_bank(&mmc1, 0x8000), 0);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 7);
+
+        for &bit in &[0x00, 0x01, 0x00, 0x00] {
+            mmc1.write_prg(0xE000, bit);
+            assert_eq!(prg_bank(&mmc1, 0x8000), 0);
+        }
+        mmc1.write_prg(0xE000, 0x00);
+        assert_eq!(prg_bank(&mmc1, 0x8000), 2);
+        assert_eq!(mmc1.shift_count, 0);
+    }
+
+    #[test]
+    fn bit7_resets_shift_register_and_fixes_last_bank() {
+        let mut mmc1 = new_mmc1();
+        write_register(&mut mmc1, 0x8000, 0x00); // 32KB mode
+        write_register(&mut mmc1, 0xE000, 0x03);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 3);
+
+        mmc1.write_prg(0x8000, 0x01);
+        mmc1.write_prg(0x8000, 0x01);
+        mmc1.write_prg(0x8000, 0x80);
+        assert_eq!(mmc1.control & 0x0C, 0x0C);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 7);
+
+        // the two writes before the reset are discarded
+        write_register(&mut mmc1, 0xE000, 0x05);
+        assert_eq!(prg_bank(&mmc1, 0x8000), 5);
+    }
+
+    #[test]
+    fn prg_bank_modes() {
+        let mut mmc1 = new_mmc1();
+        write_register(&mut mmc1, 0xE000, 0x03);
+
+        write_register(&mut mmc1, 0x8000, 0x04); // 32KB, low bit ignored
+        assert_eq!(prg_bank(&mmc1, 0x8000), 2);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 3);
+
+        write_register(&mut mmc1, 0x8000, 0x08); // first bank fixed at $8000
+        assert_eq!(prg_bank(&mmc1, 0x8000), 0);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 3);
+
+        write_register(&mut mmc1, 0x8000, 0x0C); // last bank fixed at $C000
+        assert_eq!(prg_bank(&mmc1, 0x8000), 3);
+        assert_eq!(prg_bank(&mmc1, 0xC000), 7);
+    }
+
+    #[test]
+    fn chr_bank_modes() {
+        let mut mmc1 = new_mmc1();
+        write_register(&mut mmc1, 0xA000, 0x05);
+        write_register(&mut mmc1, 0xC000, 0x02);
+
+        write_register(&mut mmc1, 0x8000, 0x0C); // 8KB, low bit ignored
+        assert_eq!(chr_bank(&mmc1, 0x0000), 4);
+        assert_eq!(chr_bank(&mmc1, 0x1000), 5);
+
+        write_register(&mut mmc1, 0x8000, 0x1C); // two 4KB banks
+        assert_eq!(chr_bank(&mmc1, 0x0000), 5);
+        assert_eq!(chr_bank(&mmc1, 0x1000), 2);
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_shift_count() {
+        let mut mmc1 = new_mmc1();
+        mmc1.shift_count = 8;
+        let mut state = StateWriter::payload();
+        mmc1.save_state(&mut state);
+        let data = state.into_bytes();
+        assert_eq!(
+            new_mmc1().load_state(&mut StateReader::payload(&data)),
+            Err(StateError::InvalidValue("MMC1 shift count"))
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// A12 must stay low for a while(about 3 CPU cycles) before a rise clocks the counter
+const A12_FILTER_CYCLES: u64 = 10;
+
+// mapper 4
+pub struct Mmc3 {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+
+    bank_select: u8,     // $8000
+    registers: [u8; 8],  // $8001 (R0-R7)
+    mirroring: Mirroring, // $A000
+
+    irq_latch: u8,    // $C000
+    irq_counter: u8,
+    irq_reload: bool, // $C001
+    irq_enabled: bool, // $E000/$E001
+    irq_pending: bool,
+
+    a12: bool,
+    a12_low_cycle: u64,
+
+    prg_offsets: [usize; 4], // $8000, $A000, $C000, $E000
+    chr_offsets: [usize; 8], // $0000, $0400, ... $1C00
+}
+
+impl Mmc3 {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        let mirroring = rom.mirroring();
+        let mut mmc3 = Mmc3 {
+            rom: rom,
+            chr: chr,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycle: 0,
+            prg_offsets: [0; 4],
+            chr_offsets: [0; 8],
+        };
+        mmc3.update_offsets();
+        mmc3
+    }
+
+    fn update_offsets(&mut self) {
+        let prg_banks = cmp::max(self.rom.prg().len() / PRG_BANK_SIZE, 2);
+        let second_last = prg_banks - 2;
+        let last = prg_banks - 1;
+        let r6 = self.registers[6] as usize & 0x3F;
+        let r7 = self.registers[7] as usize & 0x3F;
+        let banks = if (self.bank_select & 0x40) == 0 {
+            [r6, r7, second_last, last]
+        } else {
+            [second_last, r7, r6, last]
+        };
+        for (offset, bank) in self.prg_offsets.iter_mut().zip(banks.iter()) {
+            *offset = (bank % prg_banks) * PRG_BANK_SIZE;
+        }
+
+        let chr_banks = cmp::max(self.chr.len() / CHR_BANK_SIZE, 1);
+        let r = &self.registers;
+        // R0/R1 select 2KB banks
+        let mut banks = [
+            r[0] as usize & 0xFE,
+            r[0] as usize | 0x01,
+            r[1] as usize & 0xFE,
+            r[1] as usize | 0x01,
+            r[2] as usize,
+            r[3] as usize,
+            r[4] as usize,
+            r[5] as usize,
+        ];
+        if (self.bank_select & 0x80) != 0 {
+            // CHR A12 inversion
+            let (low, high) = banks.split_at_mut(4);
+            low.swap_with_slice(high);
+        }
+        for (offset, bank) in self.chr_offsets.iter_mut().zip(banks.iter()) {
+            *offset = (bank % chr_banks) * CHR_BANK_SIZE;
+        }
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = (addr as usize / CHR_BANK_SIZE) & 0x07;
+        self.chr_offsets[bank] + (addr as usize % CHR_BANK_SIZE)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = ((addr as usize - 0x8000) / PRG_BANK_SIZE) & 0x03;
+        let index = self.prg_offsets[bank] + (addr as usize % PRG_BANK_SIZE);
+        self.rom.prg()[index]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        info!("Mmc3::write_prg({:04x}, {:02x})", addr, data);
+        let is_even = (addr & 0x01) == 0;
+        match (addr, is_even) {
+            (0x8000...0x9FFF, true) => {
+                self.bank_select = data;
+                self.update_offsets();
+            }
+            (0x8000...0x9FFF, false) => {
+                let index = (self.bank_select & 0x07) as usize;
+                self.registers[index] = data;
+                self.update_offsets();
+            }
+            (0xA000...0xBFFF, true) if self.mirroring != Mirroring::FourScreen => {
+                self.mirroring = if (data & 0x01) == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            (0xA000...0xBFFF, _) => {
+                // PRG RAM protect, or mirroring of four-screen board(hardwired)
+            }
+            (0xC000...0xDFFF, true) => self.irq_latch = data,
+            (0xC000...0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000...0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xE000...0xFFFF, false) => self.irq_enabled = true,
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn ppu_address(&mut self, addr: u16, ppu_cycle: u64) {
+        let a12 = (addr & 0x1000) != 0;
+        if a12 && !self.a12 {
+            if ppu_cycle.wrapping_sub(self.a12_low_cycle) >= A12_FILTER_CYCLES {
+                self.clock_irq_counter();
+            }
+        } else if !a12 && self.a12 {
+            self.a12_low_cycle = ppu_cycle;
+        }
+        self.a12 = a12;
+    }
+
+    fn is_raise_irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.bank_select);
+        state.write_bytes(&self.registers);
+        state.write_bool(self.mirroring == Mirroring::Horizontal);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_bool(self.irq_reload);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.a12);
+        state.write_u64(self.a12_low_cycle);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.bank_select = state.read_u8()?;
+        state.read_bytes_into(&mut self.registers)?;
+        let is_horizontal = state.read_bool()?;
+        if self.mirroring != Mirroring::FourScreen {
+            self.mirroring = if is_horizontal {
+                Mirroring::Horizontal
+            } else {
+                Mirroring::Vertical
+            };
+        }
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_reload = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.a12 = state.read_bool()?;
+        self.a12_low_cycle = state.read_u64()?;
+        self.chr.load_state(state)?;
+        self.update_offsets();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    // 64KB PRG(8 x 8KB), 8KB CHR(8 x 1KB)
+    fn new_mmc3() -> Mmc3 {
+        Mmc3::new(mapper::test_rom(4, 0x10000, 0x2000))
+    }
+
+    // A12 stays low long enough, then rises like the sprite fetch of a scanline
+    fn clock_a12(mmc3: &mut Mmc3, ppu_cycle: &mut u64) {
+        mmc3.ppu_address(0x0000, *ppu_cycle);
+        *ppu_cycle += 300;
+        mmc3.ppu_address(0x1000, *ppu_cycle);
+        *ppu_cycle += 40;
+    }
+
+    #[test]
+    fn counter_reloads_and_raises_irq_at_zero() {
+        let mut mmc3 = new_mmc3();
+        let mut cycle = 0;
+        mmc3.write_prg(0xC000, 3);
+        mmc3.write_prg(0xC001, 0);
+        mmc3.write_prg(0xE001, 0);
+
+        clock_a12(&mut mmc3, &mut cycle); // reload to 3
+        assert_eq!(mmc3.irq_counter, 3);
+        clock_a12(&mut mmc3, &mut cycle);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(!mmc3.is_raise_irq());
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(mmc3.is_raise_irq());
+
+        // reloaded from the latch after reaching zero
+        clock_a12(&mut mmc3, &mut cycle);
+        assert_eq!(mmc3.irq_counter, 3);
+    }
+
+    #[test]
+    fn a12_rises_close_together_are_filtered() {
+        let mut mmc3 = new_mmc3();
+        mmc3.write_prg(0xC000, 5);
+        mmc3.write_prg(0xC001, 0);
+        mmc3.ppu_address(0x1000, 100); // reload to 5
+        for cycle in 101..110 {
+            let addr = if (cycle & 0x01) == 0 { 0x1000 } else { 0x0000 };
+            mmc3.ppu_address(addr, cycle);
+        }
+        assert_eq!(mmc3.irq_counter, 5);
+    }
+
+    #[test]
+    fn c001_reloads_on_next_clock() {
+        let mut mmc3 = new_mmc3();
+        let mut cycle = 0;
+        mmc3.write_prg(0xC000, 4);
+        mmc3.write_prg(0xC001, 0);
+        clock_a12(&mut mmc3, &mut cycle);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert_eq!(mmc3.irq_counter, 3);
+
+        mmc3.write_prg(0xC000, 8);
+        mmc3.write_prg(0xC001, 0);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert_eq!(mmc3.irq_counter, 8);
+    }
+
+    #[test]
+    fn e000_disables_and_acknowledges_irq() {
+        let mut mmc3 = new_mmc3();
+        let mut cycle = 0;
+        mmc3.write_prg(0xC000, 1);
+        mmc3.write_prg(0xC001, 0);
+        clock_a12(&mut mmc3, &mut cycle);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(!mmc3.is_raise_irq(), "IRQ is disabled at power up");
+
+        mmc3.write_prg(0xE001, 0);
+        clock_a12(&mut mmc3, &mut cycle);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(mmc3.is_raise_irq());
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(mmc3.is_raise_irq(), "IRQ is held until acknowledged");
+
+        mmc3.write_prg(0xE000, 0);
+        assert!(!mmc3.is_raise_irq());
+        clock_a12(&mut mmc3, &mut cycle);
+        clock_a12(&mut mmc3, &mut cycle);
+        assert!(!mmc3.is_raise_irq());
+    }
+
+    #[test]
+    fn bank_select_bit6_swaps_8000_and_c000() {
+        let mut mmc3 = new_mmc3();
+        mmc3.write_prg(0x8000, 0x06);
+        mmc3.write_prg(0x8001, 0x02);
+        mmc3.write_prg(0x8000, 0x07);
+        mmc3.write_prg(0x8001, 0x03);
+        let banks = |mmc3: &Mmc3| {
+            [0x8000, 0xA000, 0xC000, 0xE000]
+                .iter()
+                .map(|&addr| mmc3.read_prg(addr) / 8)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(banks(&mmc3), vec![2, 3, 6, 7]);
+
+        mmc3.write_prg(0x8000, 0x40);
+        assert_eq!(banks(&mmc3), vec![6, 3, 2, 7]);
+    }
+
+    #[test]
+    fn bank_select_bit7_inverts_chr_a12() {
+        let mut mmc3 = new_mmc3();
+        mmc3.write_prg(0x8000, 0x00);
+        mmc3.write_prg(0x8001, 0x02); // R0: 2KB at $0000
+        mmc3.write_prg(0x8000, 0x02);
+        mmc3.write_prg(0x8001, 0x07); // R2: 1KB at $1000
+        assert_eq!(mmc3.read_chr(0x0000), 2);
+        assert_eq!(mmc3.read_chr(0x0400), 3);
+        assert_eq!(mmc3.read_chr(0x1000), 7);
+
+        mmc3.write_prg(0x8000, 0x80);
+        assert_eq!(mmc3.read_chr(0x1000), 2);
+        assert_eq!(mmc3.read_chr(0x1400), 3);
+        assert_eq!(mmc3.read_chr(0x0000), 7);
+    }
+}
@@ -0,0 +1,133 @@
+mod axrom;
+mod cnrom;
+mod mmc1;
+mod mmc3;
+mod nrom;
+mod uxrom;
+
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+use self::axrom::Axrom;
+use self::cnrom::Cnrom;
+use self::mmc1::Mmc1;
+use self::mmc3::Mmc3;
+use self::nrom::Nrom;
+use self::uxrom::Uxrom;
+
+// nametable arrangement, mappers may switch it at runtime
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen, // cartridge provides extra 2KB VRAM
+}
+
+// cartridge board.
+// PRG is mapped to $8000-$FFFF of CPU, CHR is mapped to $0000-$1FFF of PPU
+pub trait Mapper {
+    fn read_prg(&self, addr: u16) -> u8;
+    fn write_prg(&mut self, addr: u16, data: u8);
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+    fn initial_pc(&self) -> u16;
+
+    // every address the PPU puts on its bus($0000-$3EFF), for boards watching A12
+    fn ppu_address(&mut self, _addr: u16, _ppu_cycle: u64) {}
+
+    fn is_raise_irq(&self) -> bool {
+        false
+    }
+
+    // registers and CHR RAM, ROM data is not saved
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub fn new_mapper(rom: Box<Rom>) -> Box<dyn Mapper> {
+    match rom.mapper_no() {
+        0 => Box::new(Nrom::new(rom)),
+        1 => Box::new(Mmc1::new(rom)),
+        2 => Box::new(Uxrom::new(rom)),
+        3 => Box::new(Cnrom::new(rom)),
+        4 => Box::new(Mmc3::new(rom)),
+        7 => Box::new(Axrom::new(rom)),
+        no => panic!("unsupported mapper:{}", no),
+    }
+}
+
+pub fn is_supported(mapper_no: u16) -> bool {
+    matches!(mapper_no, 0 | 1 | 2 | 3 | 4 | 7)
+}
+
+pub fn empty() -> Box<dyn Mapper> {
+    Box::new(Nrom::new(Rom::empty()))
+}
+
+// ROM for mapper tests, every 1KB of PRG and CHR is filled with its index
+#[cfg(test)]
+pub fn test_rom(mapper_no: u8, prg_size: usize, chr_size: usize) -> Box<Rom> {
+    let mut data = vec![0x4e, 0x45, 0x53, 0x1a, (prg_size / 0x4000) as u8, (chr_size / 0x2000) as u8];
+    data.extend_from_slice(&[mapper_no << 4, mapper_no & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend((0..prg_size).map(|i| (i / 0x400) as u8));
+    data.extend((0..chr_size).map(|i| (i / 0x400) as u8));
+    Rom::from_bytes(&data).unwrap()
+}
+
+// pattern tables on the cartridge: copy of CHR ROM, or CHR RAM(at least 8KB) when the cartridge has no CHR ROM
+pub struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool,
+}
+
+impl ChrMemory {
+    pub fn new(rom: &Rom) -> Self {
+        if rom.chr().is_empty() {
+            ChrMemory {
+                data: vec![0u8; cmp::max(rom.chr_ram_size(), 0x2000)],
+                is_ram: true,
+            }
+        } else {
+            ChrMemory {
+                data: rom.chr().to_vec(),
+                is_ram: false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn read(&self, index: usize) -> u8 {
+        self.data[index]
+    }
+
+    pub fn write(&mut self, index: usize, data: u8) {
+        if self.is_ram {
+            self.data[index] = data;
+        } else {
+            debug!("ChrMemory::write({:04x}, {:02x}) ignored, CHR ROM", index, data);
+        }
+    }
+
+    // CHR ROM is saved as empty
+    pub fn save_state(&self, state: &mut StateWriter) {
+        if self.is_ram {
+            state.write_bytes(&self.data);
+        } else {
+            state.write_bytes(&[]);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if self.is_ram {
+            state.read_bytes_into(&mut self.data)
+        } else {
+            state.read_bytes_into(&mut [])
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+
+// mapper 0
+pub struct Nrom {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+}
+
+impl Nrom {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        Nrom { rom: rom, chr: chr }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.rom.read_prg(addr & 0x7FFF)
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        info!("Nrom::write_prg({:04x}, {:02x}) ignored", addr, data);
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.chr.load_state(state)
+    }
+}
@@ -0,0 +1,91 @@
+use nes::mapper::{ChrMemory, Mapper, Mirroring};
+use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
+use std::cmp;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+// mapper 2
+pub struct Uxrom {
+    rom: Box<Rom>,
+    chr: ChrMemory,
+    prg_bank: u8, // $8000-$BFFF, $C000-$FFFF is fixed to the last bank
+}
+
+impl Uxrom {
+    pub fn new(rom: Box<Rom>) -> Self {
+        let chr = ChrMemory::new(&rom);
+        Uxrom {
+            rom: rom,
+            chr: chr,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        cmp::max(self.rom.prg().len() / PRG_BANK_SIZE, 1)
+    }
+}
+
+impl Mapper for Uxrom {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x8000...0xBFFF => self.prg_bank as usize % self.prg_banks(),
+            _ => self.prg_banks() - 1,
+        };
+        self.rom.prg()[bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)]
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) {
+        info!("Uxrom::write_prg({:04x}, {:02x})", addr, data);
+        self.prg_bank = data;
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+
+    fn initial_pc(&self) -> u16 {
+        self.rom.initial_pc()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.prg_bank);
+        self.chr.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.prg_bank = state.read_u8()?;
+        self.chr.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    #[test]
+    fn switches_bank_at_8000_and_fixes_last_bank() {
+        let mut uxrom = Uxrom::new(mapper::test_rom(2, 0x20000, 0));
+        assert_eq!(uxrom.read_prg(0x8000), 0);
+        assert_eq!(uxrom.read_prg(0xC000), 7 * 16);
+
+        uxrom.write_prg(0x8000, 0x03);
+        assert_eq!(uxrom.read_prg(0x8000), 3 * 16);
+        assert_eq!(uxrom.read_prg(0xBFFF), 3 * 16 + 15);
+        assert_eq!(uxrom.read_prg(0xC000), 7 * 16);
+
+        // bank number wraps by ROM size
+        uxrom.write_prg(0xFFFF, 0x09);
+        assert_eq!(uxrom.read_prg(0x8000), 16);
+    }
+}
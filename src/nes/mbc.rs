@@ -1,17 +1,24 @@
 use nes::rom::Rom;
+use nes::apu::Apu;
 use nes::ppu::Ppu;
-use nes::mapper::Mapper;
+use nes::mapper::{self, Mapper};
 use nes::joypad::Joypad;
+use nes::state::{StateError, StateReader, StateWriter};
 use std::cell::RefCell;
+use std::cmp;
 use std::rc::Rc;
 use std::fs::File;
 use std::io::prelude::*;
 
 pub struct Mbc {
-    mapper: Rc<RefCell<Box<Mapper>>>,
+    mapper: Rc<RefCell<Box<dyn Mapper>>>,
     // vrom: &u8,
     ram: Box<[u8]>,
+    prg_ram: Vec<u8>, // $6000-$7FFF, battery-backed on some cartridges
+    has_battery: bool,
+    is_prg_ram_dirty: bool, // changed since loaded or saved to the save file
     ppu: Rc<RefCell<Box<Ppu>>>,
+    apu: Rc<RefCell<Box<Apu>>>,
     joypad: Rc<RefCell<Box<Joypad>>>,
     // sram: &u8,
     // vram: &u8,
@@ -19,20 +26,42 @@ pub struct Mbc {
 
 impl Mbc {
     pub fn new(
-        mapper: Rc<RefCell<Box<Mapper>>>,
+        mapper: Rc<RefCell<Box<dyn Mapper>>>,
         ppu: Rc<RefCell<Box<Ppu>>>,
+        apu: Rc<RefCell<Box<Apu>>>,
         joypad: Rc<RefCell<Box<Joypad>>>,
     ) -> Self {
         Mbc {
             mapper: mapper,
             ppu: ppu,
+            apu: apu,
             joypad: joypad,
             ram: Box::new([0u8; 0x2000]),
+            prg_ram: vec![0u8; 0x2000],
+            has_battery: false,
+            is_prg_ram_dirty: false,
         }
     }
 
     pub fn set_rom(&mut self, rom: Box<Rom>) {
-        self.mapper.borrow_mut().set_rom(rom);
+        // trainer is placed at $7000, so PRG RAM covers $6000-$7FFF even if the header declares less
+        let prg_ram_size = match rom.trainer() {
+            Some(_) => cmp::max(rom.prg_ram_size(), 0x2000),
+            None => rom.prg_ram_size(),
+        };
+        self.prg_ram = vec![0u8; prg_ram_size];
+        self.has_battery = rom.has_battery();
+        self.is_prg_ram_dirty = false;
+        if let Some(trainer) = rom.trainer() {
+            let start = 0x1000;
+            self.prg_ram[start..(start + trainer.len())].copy_from_slice(trainer);
+        }
+        *self.mapper.borrow_mut() = mapper::new_mapper(rom);
+    }
+
+    // (line, dot) of PPU
+    pub fn ppu_position(&self) -> (i16, i16) {
+        self.ppu.borrow().position()
     }
 
     pub fn initial_pc(&self) -> u16 {
@@ -43,21 +72,33 @@ impl Mbc {
         let x = match addr {
             0x0000u16...0x1FFFu16 => self.ram[addr as usize],
             0x2000u16...0x3FFFu16 => self.ppu.borrow_mut().read(addr & 0x2007),
+            0x4015u16 => self.apu.borrow_mut().read(addr),
             0x4016u16...0x4017u16 => self.joypad.borrow_mut().read(addr),
-            0x6000u16...0x7FFFu16 => {
-                // self.sram[],
-                0x00u8
-            }
-            0x8000u16...0xFFFFu16 => {
-                let r = addr & 0x7FFFu16;
-                self.mapper.borrow().read_prg(r)
-            }
+            0x6000u16...0x7FFFu16 => match self.prg_ram_index(addr) {
+                Some(index) => self.prg_ram[index],
+                None => 0x00u8,
+            },
+            0x8000u16...0xFFFFu16 => self.mapper.borrow().read_prg(addr),
             _ => panic!("mbc read error:#{:x}", addr),
         };
         info!("Mbc::read({:04x}) -> {:x}", addr, x);
         x as u8
     }
 
+    // read memory without side effects, None for I/O registers
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000u16...0x1FFFu16 => Some(self.ram[addr as usize]),
+            0x6000u16...0x7FFFu16 => self.prg_ram_index(addr).map(|index| self.prg_ram[index]),
+            0x8000u16...0xFFFFu16 => Some(self.mapper.borrow().read_prg(addr)),
+            _ => None,
+        }
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
     pub fn read16(&self, addr: u16) -> u16 {
         let low = self.read(addr) as u16;
         let high = self.read(addr + 1) as u16;
@@ -70,21 +111,73 @@ impl Mbc {
             0x0000u16...0x1FFFu16 => self.ram[addr as usize] = value,
             0x2000u16...0x3FFFu16 => self.ppu.borrow_mut().write(addr & 0x2007, value),
             // 0x2000u16...0x3FFFu16 => self.io[], // dont use
-            0x4000u16...0x4013u16 => {} // ignore(APU, etc)
+            0x4000u16...0x4013u16 => self.apu.borrow_mut().write(addr, value),
             0x4014u16 => self.ppu.borrow_mut().write(addr, value),
-            0x4015u16 => {
-                // ignore
-            }
-            0x4016u16...0x4017u16 => self.joypad.borrow_mut().write(addr, value),
+            0x4015u16 => self.apu.borrow_mut().write(addr, value),
+            0x4016u16 => self.joypad.borrow_mut().write(addr, value),
+            0x4017u16 => self.apu.borrow_mut().write(addr, value), // frame counter
             // 0x4020u16...0x5FFFu16 => self.io[], // extend ram
-            // 0x6000u16...0x7FFFu16 => self.sram[],
-            0x8000u16...0xFFFFu16 => panic!("cant write to ROM:{:x}", addr),
+            0x6000u16...0x7FFFu16 => {
+                if let Some(index) = self.prg_ram_index(addr) {
+                    self.prg_ram[index] = value;
+                    self.is_prg_ram_dirty = true;
+                }
+            }
+            0x8000u16...0xFFFFu16 => self.mapper.borrow_mut().write_prg(addr, value),
             _ => panic!("mbc write error:#{:x}", addr),
         };
     }
 
-    pub fn mapper(&self) -> Rc<RefCell<Box<Mapper>>> {
-        self.mapper.clone()
+    // PRG RAM smaller than 8KB is mirrored, larger one shows only the first 8KB
+    fn prg_ram_index(&self, addr: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            // NES 2.0 header can declare no PRG RAM
+            return None;
+        }
+        Some((addr as usize - 0x6000) % self.prg_ram.len())
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.has_battery
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn is_prg_ram_dirty(&self) -> bool {
+        self.is_prg_ram_dirty
+    }
+
+    pub fn clear_prg_ram_dirty(&mut self) {
+        self.is_prg_ram_dirty = false;
+    }
+
+    // restore saved PRG RAM, ignore extra bytes of a mismatched save file
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let size = cmp::min(self.prg_ram.len(), data.len());
+        self.prg_ram[..size].copy_from_slice(&data[..size]);
+        self.is_prg_ram_dirty = false;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bytes(&self.prg_ram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes_into(&mut self.ram)?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        self.is_prg_ram_dirty = true;
+        Ok(())
+    }
+
+    pub fn save_mapper_state(&self, state: &mut StateWriter) {
+        self.mapper.borrow().save_state(state);
+    }
+
+    pub fn load_mapper_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.borrow_mut().load_state(state)
     }
 
     pub fn is_enable_nmi(&self) -> bool {
@@ -95,8 +188,13 @@ impl Mbc {
         self.ppu.borrow_mut().is_raise_nmi()
     }
 
+    // IRQ line is shared by APU(frame counter, DMC) and cartridge
+    pub fn is_raise_irq(&self) -> bool {
+        self.apu.borrow().is_raise_irq() || self.mapper.borrow().is_raise_irq()
+    }
+
     pub fn dump_ram(&self) {
         let mut file = File::create("ram.dmp").unwrap();
-        let _ = file.write_all(&self.ram).unwrap();
+        file.write_all(&self.ram).unwrap();
     }
 }
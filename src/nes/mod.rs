@@ -1,50 +1,73 @@
 extern crate bmp;
 
+mod apu;
 mod cpu;
 mod mapper;
 mod mbc;
 mod ppu;
+mod state;
+pub mod disasm;
 pub mod joypad;
+pub mod rewind;
 pub mod rom;
 
+pub use nes::mapper::Mirroring;
+pub use nes::state::StateError;
+
 use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
 use std::rc::Rc;
+use nes::apu::Apu;
 use nes::cpu::Cpu;
 use nes::mbc::Mbc;
 use nes::joypad::Joypad;
 use nes::ppu::Ppu;
-use nes::mapper::Mapper;
-use nes::bmp::Image;
+use nes::state::{StateReader, StateWriter};
 
 pub struct Nes {
     cpu: Cpu,
     mbc: Rc<RefCell<Box<Mbc>>>,
     ppu: Rc<RefCell<Box<Ppu>>>,
+    apu: Rc<RefCell<Box<Apu>>>,
     joypad: Rc<RefCell<Box<Joypad>>>,
+    rom_checksum: u64, // save states are bound to the ROM
     // tick: u32,
 }
 
 macro_rules !wrap_rc {
     ($value: expr) => {
-        Rc::new(RefCell::new(Box::new($value)));
+        Rc::new(RefCell::new(Box::new($value)))
+    }
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Nes {
     pub fn new() -> Self {
-        let mapper = wrap_rc!(Mapper::new());
+        let mapper = Rc::new(RefCell::new(mapper::empty()));
         let ppu = wrap_rc!(Ppu::new(mapper.clone()));
+        let apu = wrap_rc!(Apu::new());
         let joypad = wrap_rc!(Joypad::new());
-        let mbc = wrap_rc!(Mbc::new(mapper.clone(), ppu.clone(), joypad.clone()));
+        let mbc = wrap_rc!(Mbc::new(mapper.clone(), ppu.clone(), apu.clone(), joypad.clone()));
 
         ppu.borrow_mut().set_mbc(Rc::downgrade(&mbc));
+        apu.borrow_mut().set_mbc(Rc::downgrade(&mbc));
         let cpu = Cpu::new(mbc.clone());
 
         Nes {
             cpu: cpu,
             mbc: mbc,
             ppu: ppu,
+            apu: apu,
             joypad: joypad,
+            rom_checksum: 0,
         }
     }
 
@@ -63,8 +86,17 @@ impl Nes {
         self.ppu.borrow_mut().screen_rendered()
     }
 
-    pub fn reset_screen_rendered(&self) -> bool {
-        self.ppu.borrow_mut().screen_rendered()
+    pub fn reset_screen_rendered(&self) {
+        self.ppu.borrow_mut().reset_screen_rendered()
+    }
+
+    // run until PPU finishes a frame
+    pub fn step_frame(&mut self) {
+        self.reset_screen_rendered();
+        while !self.screen_rendered() {
+            self.tick();
+        }
+        self.reset_screen_rendered();
     }
 
     #[inline(never)]
@@ -76,28 +108,298 @@ impl Nes {
             self.ppu.borrow_mut().tick();
         } else {
             self.cpu.tick();
+            let spent = self.cpu.cycle() - cpu_cycle;
+            let mut apu = self.apu.borrow_mut();
+            for _ in 0..spent {
+                apu.tick();
+            }
         }
     }
 
     pub fn set_rom(&mut self, rom: Box<rom::Rom>) {
+        self.rom_checksum = state::checksum(&[rom.prg(), rom.chr()]);
         self.mbc.borrow_mut().set_rom(rom);
         self.cpu.setup();
-        self.ppu.borrow_mut().setup();
+    }
+
+    // load ROM file, then power on
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<(), rom::RomError> {
+        let rom = rom::Rom::load(path)?;
+        self.set_rom(rom);
+        self.reset();
+        Ok(())
     }
 
     pub fn reset(&mut self) {
         self.cpu.reset();
     }
 
+    // start point for test ROMs, e.g. $C000 for nestest without display
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.pc = pc;
+    }
+
+    // log every instruction in nestest.log format
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.cpu.set_trace(enabled);
+    }
+
+    // move trace lines to buffer
+    pub fn take_trace(&mut self, buffer: &mut Vec<String>) {
+        self.cpu.take_trace(buffer);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+
+    // listing of count instructions from addr in current memory, for debuggers
+    pub fn disassemble(&self, addr: u16, count: usize, symbols: Option<&disasm::Symbols>) -> Vec<String> {
+        let mbc = self.mbc.borrow();
+        let read = |addr| mbc.peek(addr).unwrap_or(0xFF);
+        let mut lines = vec![];
+        let mut addr = addr;
+        for _ in 0..count {
+            let instruction = disasm::Instruction::decode(addr, read);
+            let text = instruction.format_resolved(self.cpu.x, self.cpu.y, read, symbols);
+            lines.push(disasm::format_line(&instruction, &text));
+            addr = addr.wrapping_add(instruction.length());
+        }
+        lines
+    }
+
     pub fn screen_size(&self) -> (u32, u32) {
         (ppu::SCREEN_WIDTH as u32, ppu::SCREEN_HEIGHT as u32)
     }
 
-    pub fn render_image(&self, img: &mut Vec<u8>) {
+    // RGBA(B, G, R, unused) of SCREEN_WIDTH x SCREEN_HEIGHT
+    pub fn render_image(&self, img: &mut [u8]) {
         self.ppu.borrow().render_image(img)
     }
 
+    pub fn set_audio_sample_rate(&self, sample_rate: u32) {
+        self.apu.borrow_mut().set_sample_rate(sample_rate);
+    }
+
+    // append generated audio samples(mono, f32) to buffer
+    pub fn take_audio_samples(&self, buffer: &mut Vec<f32>) {
+        self.apu.borrow_mut().take_samples(buffer);
+    }
+
+    // load battery-backed PRG RAM, missing save file is not an error
+    pub fn load_battery_ram<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        if !self.mbc.borrow().has_battery() {
+            return Ok(());
+        }
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        self.mbc.borrow_mut().load_prg_ram(&data);
+        Ok(())
+    }
+
+    pub fn save_battery_ram<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let mut mbc = self.mbc.borrow_mut();
+        if !mbc.has_battery() {
+            return Ok(());
+        }
+        let mut file = File::create(path)?;
+        file.write_all(mbc.prg_ram())?;
+        mbc.clear_prg_ram_dirty();
+        Ok(())
+    }
+
+    // battery-backed PRG RAM was written after the last load or save
+    pub fn is_battery_ram_dirty(&self) -> bool {
+        let mbc = self.mbc.borrow();
+        mbc.has_battery() && mbc.is_prg_ram_dirty()
+    }
+
+    // CPU memory(RAM, PRG RAM, PRG ROM) without side effects, None for I/O registers
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        self.mbc.borrow().peek(addr)
+    }
+
+    // copy of internal RAM($0000-$1FFF)
+    pub fn ram(&self) -> Vec<u8> {
+        self.mbc.borrow().ram().to_vec()
+    }
+
     pub fn set_joypad_button_state(&self, state: u8) {
         self.joypad.borrow_mut().set_button_state(state);
     }
+
+    // snapshot of the whole machine, format is described in state.rs
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.rom_checksum);
+        state.section(b"CPU ", |state| self.cpu.save_state(state));
+        state.section(b"PPU ", |state| self.ppu.borrow().save_state(state));
+        state.section(b"APU ", |state| self.apu.borrow().save_state(state));
+        state.section(b"MBC ", |state| self.mbc.borrow().save_state(state));
+        state.section(b"MAPR", |state| self.mbc.borrow().save_mapper_state(state));
+        state.section(b"JOYP", |state| self.joypad.borrow().save_state(state));
+        state.into_bytes()
+    }
+
+    // machine is left untouched when the state is rejected
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, self.rom_checksum)?;
+        let backup = self.save_state();
+        if let Err(err) = self.load_sections(&mut state) {
+            let mut backup = StateReader::new(&backup, self.rom_checksum).unwrap();
+            self.load_sections(&mut backup).unwrap();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn load_sections(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(&mut state.section(b"CPU ")?)?;
+        self.ppu.borrow_mut().load_state(&mut state.section(b"PPU ")?)?;
+        self.apu.borrow_mut().load_state(&mut state.section(b"APU ")?)?;
+        self.mbc.borrow_mut().load_state(&mut state.section(b"MBC ")?)?;
+        self.mbc.borrow_mut().load_mapper_state(&mut state.section(b"MAPR")?)?;
+        self.joypad.borrow_mut().load_state(&mut state.section(b"JOYP")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NROM-128 counting in RAM forever with rendering enabled
+    fn test_rom(seed: u8) -> Box<rom::Rom> {
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEAu8; 0x4000];
+        let program = [
+            0xA9, 0x1E, // LDA #$1E
+            0x8D, 0x01, 0x20, // STA $2001
+            0xE6, 0x00, // INC $00
+            0xA5, 0x00, // LDA $00
+            0x65, 0x01, // ADC $01
+            0x85, 0x01, // STA $01
+            0x4C, 0x05, 0xC0, // JMP $C005
+        ];
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFC] = 0x00; // reset vector
+        prg[0x3FFD] = 0xC0;
+        data.extend(prg);
+        data.extend((0..0x2000).map(|i| (i as u8).wrapping_add(seed)));
+        rom::Rom::from_bytes(&data).unwrap()
+    }
+
+    fn battery_rom() -> Box<rom::Rom> {
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, 1, 0, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend(vec![0xEAu8; 0x4000]);
+        rom::Rom::from_bytes(&data).unwrap()
+    }
+
+    fn new_nes(seed: u8) -> Nes {
+        let mut nes = Nes::new();
+        nes.set_rom(test_rom(seed));
+        nes.reset();
+        nes
+    }
+
+    fn run_frames(nes: &mut Nes, frames: usize) -> (Vec<u8>, Vec<u8>) {
+        for _ in 0..frames {
+            nes.step_frame();
+        }
+        let (width, height) = nes.screen_size();
+        let mut img = vec![0u8; (width * height * 4) as usize];
+        nes.render_image(&mut img);
+        (nes.ram(), img)
+    }
+
+    #[test]
+    fn load_state_replays_same_frames() {
+        let mut nes = new_nes(0);
+        run_frames(&mut nes, 3);
+        let state = nes.save_state();
+        let expected = run_frames(&mut nes, 5);
+
+        nes.load_state(&state).unwrap();
+        assert_eq!(run_frames(&mut nes, 5), expected);
+        // saving again gives the same bytes
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state);
+    }
+
+    #[test]
+    fn rejected_state_keeps_machine() {
+        let mut nes = new_nes(0);
+        run_frames(&mut nes, 2);
+        let state = nes.save_state();
+
+        let mut other = new_nes(1);
+        assert_eq!(other.load_state(&state), Err(StateError::RomMismatch));
+
+        run_frames(&mut nes, 1);
+        let current = nes.save_state();
+        assert_eq!(nes.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(nes.save_state(), current);
+    }
+
+    #[test]
+    fn rewind_goes_back_to_earlier_frames() {
+        let mut nes = new_nes(0);
+        let mut rewind = rewind::Rewind::new(60, 1);
+        let mut frames = vec![];
+        for _ in 0..10 {
+            nes.step_frame();
+            rewind.push(&nes.save_state());
+            frames.push(nes.ram());
+        }
+        // a frame changes a few bytes, far smaller than full snapshots
+        assert!(rewind.memory_usage() < nes.save_state().len() * 2);
+
+        for expected in frames.iter().rev().take(3) {
+            nes.load_state(&rewind.pop().unwrap()).unwrap();
+            assert_eq!(&nes.ram(), expected);
+        }
+    }
+
+    #[test]
+    fn battery_ram_is_dirty_until_saved() {
+        let filename = format!("rust-nes-battery-{}.sav", ::std::process::id());
+        let path = ::std::env::temp_dir().join(filename);
+        let mut nes = Nes::new();
+        nes.set_rom(battery_rom());
+        assert!(!nes.is_battery_ram_dirty());
+
+        nes.mbc.borrow_mut().write(0x6000, 0x5A);
+        assert!(nes.is_battery_ram_dirty());
+        nes.save_battery_ram(&path).unwrap();
+        assert!(!nes.is_battery_ram_dirty());
+
+        nes.set_rom(battery_rom());
+        nes.load_battery_ram(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(nes.peek(0x6000), Some(0x5A));
+        assert!(!nes.is_battery_ram_dirty());
+    }
+
+    #[test]
+    fn unreadable_battery_ram_is_an_error() {
+        let mut nes = Nes::new();
+        nes.set_rom(battery_rom());
+        // a directory can be opened but not read
+        assert!(nes.load_battery_ram(::std::env::temp_dir()).is_err());
+    }
+
+    #[test]
+    fn trainer_is_mapped_without_declared_prg_ram() {
+        // NES 2.0 header with trainer and no PRG RAM
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, 0x04, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend((0..0x200).map(|i| (i as u8) ^ 0x5A));
+        data.extend(vec![0u8; 0x4000 + 0x2000]);
+        let mut nes = Nes::new();
+        nes.set_rom(rom::Rom::from_bytes(&data).unwrap());
+        assert_eq!(nes.peek(0x7000), Some(0x5A));
+        assert_eq!(nes.peek(0x71FF), Some(0xFF ^ 0x5A));
+    }
 }
@@ -1,16 +1,11 @@
 mod vram;
 
-use nes::bmp::Image;
-use nes::bmp::Pixel;
 use nes::mapper::Mapper;
 use nes::mbc::Mbc;
-use nes::rom::Rom;
+use nes::state::{StateError, StateReader, StateWriter};
 use std::cell::RefCell;
-use std::fs::File;
-use std::io::prelude::*;
 use std::rc::Rc;
 use std::rc::Weak;
-use std::mem;
 use self::vram::Vram;
 
 bitflags! {
@@ -72,6 +67,36 @@ bitflags! {
     }
 }
 
+impl Mask {
+    fn apply_greyscale(&self, palette_index: u8) -> u8 {
+        if self.contains(Mask::GRA) {
+            palette_index & 0x30
+        } else {
+            palette_index
+        }
+    }
+
+    fn emphasis(&self) -> u8 {
+        (*self & (Mask::EMP_RED | Mask::EMP_GREEN | Mask::EMP_BLUE)).bits()
+    }
+}
+
+// emphasized channels keep their level, the others are darkened
+fn emphasize(color: [u8; 3], emphasis: u8) -> [u8; 3] {
+    if emphasis == 0 {
+        return color;
+    }
+    // B, G, R
+    let channels = [Mask::EMP_BLUE, Mask::EMP_GREEN, Mask::EMP_RED];
+    let mut result = color;
+    for (value, channel) in result.iter_mut().zip(channels.iter()) {
+        if (emphasis & channel.bits()) == 0 {
+            *value = (*value as u16 * EMPHASIS_ATTENUATION / 256) as u8;
+        }
+    }
+    result
+}
+
 bitflags! {
     struct Status: u8 {
         const SPRITE_OVERFLOW = 0x20u8; // sprite over flow
@@ -86,7 +111,14 @@ pub struct Ppu {
     mask: Mask,               // $2001(w)
     status: Status,           // $2002(r)
     oam_address: u8,          // $2003(w)
-    scroll_position: Vec<u8>, //  $2005(w*2)
+
+    // internal registers shared by $2000/$2005/$2006(loopy's model)
+    // yyy NN YYYYY XXXXX: fine Y, nametable, coarse Y, coarse X
+    vram_address: u16,      // v: current VRAM address
+    temp_vram_address: u16, // t: address of the top left onscreen tile
+    fine_x: u8,             // x: fine X scroll(3bit)
+    write_toggle: bool,     // w: first or second write of $2005/$2006
+    io_latch: u8,           // value on PPU data bus, read back from write-only registers
     // 0x0000-0x0FFF:Pattern table1(mapped by chr rom)
     // 0x1000-0x1FFF:Pattern table2(mapped by chr rom)
     // 0x2000-0x23FF:Name table1
@@ -97,8 +129,13 @@ pub struct Ppu {
     vram: Vram,
 
     oam_ram: Vec<u8>, // for sprites
+    secondary_oam: Vec<u8>, // up to 8 sprites for next line
+    secondary_oam_count: usize,
+    secondary_oam_has_zero: bool,
+    oam_latch: u8,            // byte read from OAM by sprite evaluation, seen through $2004
+    evaluation_byte: usize,   // bytes of the in-range sprite copied so far, 0 while checking Y
+    is_evaluation_done: bool, // all sprites checked, or overflow found
     mbc: Weak<RefCell<Box<Mbc>>>,
-    mapper: Rc<RefCell<Box<Mapper>>>,
     cycle: u64,
     current_line: i16,
     current_cycle: i16,
@@ -106,11 +143,12 @@ pub struct Ppu {
     done_rendered: bool,
 
     output_frame: Vec<u8>,
+    output_emphasis: Vec<u8>, // PPUMASK emphasis bits of each pixel
 
-    fetched_background: BackgroundImage,
+    background: BackgroundPipeline,
     fetched_sprites: Vec<Sprite>,
 
-    tasks: Vec<Box<OamDmaTask>>,
+    tasks: Vec<OamDmaTask>,
 }
 
 const PALETTE_COLORS: [[u8; 3]; 64] = [
@@ -181,6 +219,9 @@ const PALETTE_COLORS: [[u8; 3]; 64] = [
     [0x00u8, 0x00u8, 0x00u8, ],
 ];
 
+// about 0.82, per non-emphasized channel(x/256)
+const EMPHASIS_ATTENUATION: u16 = 209;
+
 const PALETTE_BASE_ADDR: u16 = 0x3F00;
 const PALETTE_SPRITE_ADDR: u16 = 0x3F10;
 
@@ -194,32 +235,41 @@ const RAISE_NMI_LINE: i16 = SCREEN_HEIGHT as i16 + 1;
 const DROP_NMI_LINE: i16 = 260;
 const RAISE_VBLANK_LINE: i16 = SCREEN_HEIGHT as i16 + 1;
 const DROP_VBLANK_LINE: i16 = 260;
-const RESET_OAM_ADDRESS_LINE: i16 = SCREEN_HEIGHT as i16 + 1;
+const PRE_RENDER_LINE: i16 = -1;
+const SPRITES_PER_LINE: usize = 8;
 
 impl Ppu {
-    pub fn new(mapper: Rc<RefCell<Box<Mapper>>>) -> Self {
-        let horizontal = { mapper.borrow().is_horizontal() };
-
+    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>) -> Self {
         Ppu {
             control: Control::empty(),
             mask: Mask::empty(),
             status: Status::empty(),
             oam_address: 0u8,
-            vram: Vram::new(horizontal),
+            vram: Vram::new(mapper),
             oam_ram: vec![0x00u8; 0x0100],
+            secondary_oam: vec![0xFFu8; SPRITES_PER_LINE * 4],
+            secondary_oam_count: 0,
+            secondary_oam_has_zero: false,
+            oam_latch: 0,
+            evaluation_byte: 0,
+            is_evaluation_done: false,
             cycle: 0u64,
-            current_line: -1,
+            current_line: 0, // power up at line 0, as nestest.log does
             current_cycle: 0,
-            scroll_position: vec![0, 0],
+            vram_address: 0,
+            temp_vram_address: 0,
+            fine_x: 0,
+            write_toggle: false,
+            io_latch: 0,
             is_raise_nmi: false,
             done_rendered: false,
 
             output_frame: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
-            fetched_background: BackgroundImage::empty(),
+            output_emphasis: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+            background: BackgroundPipeline::new(),
             fetched_sprites: vec![],
 
             mbc:      Weak::default(),
-            mapper:   mapper,
             tasks   : vec![],
         }
     }
@@ -228,36 +278,33 @@ impl Ppu {
         self.mbc = mbc;
     }
 
-    pub fn setup(&mut self) {
-        // copy chr from rom
-        // TODO: direct read from rom
-        let mapper = self.mapper.borrow();
-        let chr_rom = mapper.chr_rom();
-        for i in 0..chr_rom.len() {
-            self.vram.write(i as u16, chr_rom[i]);
-        }
-    }
-
     pub fn cycle(&self) -> u64 {
         self.cycle
     }
 
+    // (line, dot) of the next cycle
+    pub fn position(&self) -> (i16, i16) {
+        (self.current_line, self.current_cycle)
+    }
+
     #[inline(never)]
     pub fn tick(&mut self) {
         self.cycle = self.cycle.wrapping_add(1);
-        if self.tasks.len() > 0 {
+        self.vram.set_cycle(self.cycle);
+        if !self.tasks.is_empty() {
             let task = self.tasks.pop().unwrap();
             task.call(self);
         }
         self.process_cycle();
     }
 
-    pub fn render_image(&self, img: &mut Vec<u8>) {
+    pub fn render_image(&self, img: &mut [u8]) {
         for y in 0..SCREEN_HEIGHT {
             for x in 0..SCREEN_WIDTH {
                 let mut index = (x + y * SCREEN_WIDTH) as usize;
                 let palette_index = self.output_frame[index];
-                let color = PALETTE_COLORS[palette_index as usize];
+                let color = emphasize(PALETTE_COLORS[palette_index as usize],
+                                      self.output_emphasis[index]);
                 index *= 4;
                 img[index..(index+3)].copy_from_slice(&color);
             }
@@ -269,7 +316,103 @@ impl Ppu {
     }
 
     pub fn reset_screen_rendered(&mut self) {
-        self.done_rendered = true
+        self.done_rendered = false
+    }
+
+    // frame buffer is not saved, it is redrawn by the next frame
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.control.bits());
+        state.write_u8(self.mask.bits());
+        state.write_u8(self.status.bits());
+        state.write_u8(self.oam_address);
+        state.write_u16(self.vram_address);
+        state.write_u16(self.temp_vram_address);
+        state.write_u8(self.fine_x);
+        state.write_bool(self.write_toggle);
+        state.write_u8(self.io_latch);
+
+        state.write_bytes(&self.oam_ram);
+        state.write_bytes(&self.secondary_oam);
+        state.write_u8(self.secondary_oam_count as u8);
+        state.write_bool(self.secondary_oam_has_zero);
+        state.write_u8(self.oam_latch);
+        state.write_u8(self.evaluation_byte as u8);
+        state.write_bool(self.is_evaluation_done);
+
+        state.write_u64(self.cycle);
+        state.write_i16(self.current_line);
+        state.write_i16(self.current_cycle);
+        state.write_bool(self.is_raise_nmi);
+
+        self.background.save_state(state);
+        state.write_u8(self.fetched_sprites.len() as u8);
+        for sprite in self.fetched_sprites.iter() {
+            sprite.save_state(state);
+        }
+        state.write_u8(self.tasks.len() as u8);
+        for task in self.tasks.iter() {
+            state.write_u16(task.source);
+        }
+
+        self.vram.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.control = Control::from_bits_truncate(state.read_u8()?);
+        self.mask = Mask::from_bits_truncate(state.read_u8()?);
+        self.status = Status::from_bits_truncate(state.read_u8()?);
+        self.oam_address = state.read_u8()?;
+        self.vram_address = state.read_u16()?;
+        self.temp_vram_address = state.read_u16()?;
+        self.fine_x = state.read_u8()?;
+        if self.fine_x > 7 {
+            return Err(StateError::InvalidValue("fine X scroll"));
+        }
+        self.write_toggle = state.read_bool()?;
+        self.io_latch = state.read_u8()?;
+
+        state.read_bytes_into(&mut self.oam_ram)?;
+        state.read_bytes_into(&mut self.secondary_oam)?;
+        self.secondary_oam_count = state.read_u8()? as usize;
+        if self.secondary_oam_count > SPRITES_PER_LINE {
+            return Err(StateError::InvalidValue("secondary OAM count"));
+        }
+        self.secondary_oam_has_zero = state.read_bool()?;
+        self.oam_latch = state.read_u8()?;
+        self.evaluation_byte = state.read_u8()? as usize;
+        if self.evaluation_byte > 3 {
+            return Err(StateError::InvalidValue("sprite evaluation byte"));
+        }
+        self.is_evaluation_done = state.read_bool()?;
+
+        self.cycle = state.read_u64()?;
+        self.current_line = state.read_i16()?;
+        if self.current_line < PRE_RENDER_LINE || self.current_line >= SCANLINE_PER_SCREEN - 1 {
+            return Err(StateError::InvalidValue("scanline"));
+        }
+        self.current_cycle = state.read_i16()?;
+        if self.current_cycle < 0 || self.current_cycle >= CYCLE_PER_LINE {
+            return Err(StateError::InvalidValue("dot"));
+        }
+        self.is_raise_nmi = state.read_bool()?;
+
+        self.background.load_state(state)?;
+        let sprites = state.read_u8()? as usize;
+        if sprites > SPRITES_PER_LINE {
+            return Err(StateError::InvalidValue("sprite count"));
+        }
+        self.fetched_sprites.clear();
+        for _ in 0..sprites {
+            self.fetched_sprites.push(Sprite::load_state(state)?);
+        }
+        self.tasks.clear();
+        for _ in 0..state.read_u8()? {
+            self.tasks.push(OamDmaTask::new(state.read_u16()?));
+        }
+
+        self.vram.load_state(state)?;
+        self.vram.set_cycle(self.cycle);
+        Ok(())
     }
 
     pub fn dump(&self) {
@@ -300,21 +443,24 @@ impl Ppu {
             if self.current_line == DROP_NMI_LINE {
                 self.is_raise_nmi = false;
             }
+            if self.current_line == PRE_RENDER_LINE {
+                self.status.remove(Status::SPRITE_ZERO_HIT | Status::SPRITE_OVERFLOW);
+            }
         }
 
-        if -1 < self.current_line && self.current_line < SCREEN_HEIGHT as i16 && self.current_cycle < SCREEN_WIDTH as i16 {
-            if (self.current_cycle % 8) == 0 {
-                self.fetch_background_image();
-            }
-            if self.current_cycle == 0 {
-                self.fetch_sprites();
-            }
-            self.process_pixel();
+        if self.is_rendering_enabled() && self.current_line < SCREEN_HEIGHT as i16 {
+            self.fetch_background();
+        }
+
+        if self.is_rendering_enabled() && self.current_line < SCREEN_HEIGHT as i16 {
+            self.process_sprite_cycle();
         }
 
-        // reset OAM address
-        if RESET_OAM_ADDRESS_LINE <= self.current_line && self.current_line <= SCANLINE_PER_SCREEN {
-            self.oam_address = 0;
+        if -1 < self.current_line && self.current_line < SCREEN_HEIGHT as i16 {
+            // pixels are output at dot 1-256
+            if 0 < self.current_cycle && self.current_cycle <= SCREEN_WIDTH as i16 {
+                self.process_pixel();
+            }
         }
 
         self.update_cycle();
@@ -331,169 +477,426 @@ impl Ppu {
         }
     }
 
-    fn name_table_addr(&self) -> u16 {
-        match (self.control & Control::NAME_TABLE_ADDR).bits {
-            0 => 0x2000u16,
-            1 => 0x2400u16,
-            2 => 0x2800u16,
-            3 => 0x2C00u16,
-            _ => unreachable!(),
-        }
+    fn is_rendering_enabled(&self) -> bool {
+        self.mask.intersects(Mask::SHOW_BACKGROUND | Mask::SHOW_SPRITE)
     }
 
-    fn name_table_addr_from_point(&self, x: u16, y: u16) -> u16 {
-        let base = self.name_table_addr();
-        base + (x / 8) + (y / 8) * 32
+    fn increment_coarse_x(&mut self) {
+        if (self.vram_address & 0x001F) == 31 {
+            // wrap to next horizontal nametable
+            self.vram_address &= !0x001F;
+            self.vram_address ^= 0x0400;
+        } else {
+            self.vram_address += 1;
+        }
     }
 
-    fn attribute_addr_from_point(&mut self, x: u16, y: u16) -> u16 {
-        let base = self.name_table_addr();
-        let index_x = x / 32;
-        let index_y = (y / 32) * 8;
-        let index = index_x + index_y;
-
-        base + index + 0x03C0
+    fn increment_y(&mut self) {
+        if (self.vram_address & 0x7000) != 0x7000 {
+            self.vram_address += 0x1000; // fine Y
+            return;
+        }
+        self.vram_address &= !0x7000;
+        let mut coarse_y = (self.vram_address & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            // wrap to next vertical nametable
+            coarse_y = 0;
+            self.vram_address ^= 0x0800;
+        } else if coarse_y == 31 {
+            // out of nametable(attribute area), wrap without switching
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.vram_address = (self.vram_address & !0x03E0) | (coarse_y << 5);
     }
 
-    fn current_point(&self) -> (u16 ,u16) {
-        (
-            self.current_cycle as u16 + self.scroll_position[0] as u16,
-            self.current_line as u16 + self.scroll_position[1] as u16
-        )
+    fn increment_vram_address(&mut self) {
+        if self.is_rendering_enabled() && self.current_line < SCREEN_HEIGHT as i16 {
+            // $2007 access while rendering glitches both counters
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            let inc = self.control.nametable_increment_value();
+            self.vram_address = self.vram_address.wrapping_add(inc) & 0x7FFF;
+        }
     }
 
+    // one memory access per 2 dots: nametable, attribute, pattern low, pattern high.
+    // runs on visible lines and pre-render line, which prefetches first 2 tiles at dot 321-336
     #[inline(never)]
-    fn fetch_background_image(&mut self) {
-        let (x,y) = self.current_point();
+    fn fetch_background(&mut self) {
+        match self.current_cycle {
+            2...257 | 321...337 => {
+                self.background.shift();
+                match (self.current_cycle - 1) % 8 {
+                    0 => {
+                        self.background.load();
+                        self.background.next_tile_index = self.vram.read_internal(self.nametable_address());
+                    }
+                    2 => {
+                        let v = self.vram_address;
+                        let attribute_addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                        let attribute = self.vram.read_internal(attribute_addr);
+                        // select 2bit of 16x16 pixel area from 32x32 pixel block
+                        let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                        self.background.next_attribute = (attribute >> shift) & 0x03;
+                    }
+                    4 => {
+                        let addr = self.background_pattern_address();
+                        self.background.next_pattern_low = self.vram.read_internal(addr);
+                    }
+                    6 => {
+                        let addr = self.background_pattern_address() + 8;
+                        self.background.next_pattern_high = self.vram.read_internal(addr);
+                    }
+                    7 => self.increment_coarse_x(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
 
-        // render BG
-        let nametable_addr = self.name_table_addr_from_point(x, y);
-        let pattern_index = self.vram.read_internal(nametable_addr);
-        let pattern_addr = self.control.bg_pattern_address() + (pattern_index as u16) * 2 * 8;
+        match self.current_cycle {
+            256 => self.increment_y(),
+            257 => {
+                // copy horizontal position(coarse X, nametable X) from t
+                self.vram_address = (self.vram_address & !0x041F) | (self.temp_vram_address & 0x041F);
+            }
+            280...304 if self.current_line == PRE_RENDER_LINE => {
+                // copy vertical position(fine Y, coarse Y, nametable Y) from t
+                self.vram_address = (self.vram_address & !0x7BE0) | (self.temp_vram_address & 0x7BE0);
+            }
+            338 | 340 => {
+                // unused nametable fetches
+                let addr = self.nametable_address();
+                self.vram.read_internal(addr);
+            }
+            _ => {}
+        }
+    }
 
-        info!("fetch_background_image {},{}, ctrl:{:x}", x, y, self.control);
-        info!("pattern_index {:x}, pattern_addr {:x}", pattern_index, pattern_addr);
-        self.fetched_background = BackgroundImage::from_vram_address(pattern_addr,
-                                                                     self.attribute_addr_from_point(x, y),
-                                                                     &mut self.vram);
-        info!("fetched_background {:?}", self.fetched_background);
+    fn nametable_address(&self) -> u16 {
+        0x2000 | (self.vram_address & 0x0FFF)
     }
 
-    #[inline(never)]
-    fn fetch_sprites(&mut self) {
-        self.fetched_sprites.clear();
+    fn background_pattern_address(&self) -> u16 {
+        let fine_y = (self.vram_address >> 12) & 0x07;
+        self.control.bg_pattern_address() + (self.background.next_tile_index as u16) * 2 * 8 + fine_y
+    }
 
-        let (x,y) = self.current_point();
+    // sprites for next line: secondary OAM is cleared at dot 1-64, filled at dot 65-256,
+    // and their patterns are fetched at dot 257-320(8 dots per sprite)
+    fn process_sprite_cycle(&mut self) {
+        let is_visible_line = self.current_line != PRE_RENDER_LINE;
+        match self.current_cycle {
+            1 => {
+                // pre-render line has no sprites for line 0
+                for byte in self.secondary_oam.iter_mut() {
+                    *byte = 0xFF;
+                }
+                self.secondary_oam_count = 0;
+                self.secondary_oam_has_zero = false;
+                self.evaluation_byte = 0;
+                self.is_evaluation_done = false;
+            }
+            65...256 if is_visible_line => {
+                // odd dots read OAM, even dots write secondary OAM
+                if (self.current_cycle & 0x01) == 1 {
+                    self.oam_latch = self.oam_ram[self.oam_address as usize];
+                } else {
+                    self.evaluate_sprite();
+                }
+            }
+            257...320 => {
+                self.oam_address = 0;
+                let slot = ((self.current_cycle - 257) / 8) as usize;
+                match (self.current_cycle - 257) % 8 {
+                    0 if slot == 0 => self.fetched_sprites.clear(),
+                    4 => self.fetch_sprite_pattern(slot, false),
+                    6 => self.fetch_sprite_pattern(slot, true),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
 
-        let sprite_pattern_base_addr = self.control.sprite_pattern_addr();
-        for sprite_index in 0..64 {
-            let start = sprite_index * 4;
-            let end = start + 4;
-            let sprite = Sprite::from_oam(&self.oam_ram[start..end], &mut self.vram, sprite_pattern_base_addr);
+    // one step of sprite evaluation, checks or copies the byte read on the previous dot.
+    // OAMADDR is the pointer(sprite n: high 6 bits, byte m: low 2 bits), so $2003/$2004 writes
+    // during evaluation change which sprites are found
+    fn evaluate_sprite(&mut self) {
+        let address = self.oam_address;
+        if self.is_evaluation_done {
+            // keeps reading Y of following sprites until dot 256
+            self.oam_address = address.wrapping_add(4);
+            return;
+        }
 
-            if !sprite.in_bounding_y(y) {
-                continue;
+        let data = self.oam_latch;
+        let y = data as i16;
+        let in_range = 0 <= self.current_line - y && self.current_line - y < self.sprite_height();
+        let next = if self.secondary_oam_count < SPRITES_PER_LINE {
+            // Y is written even when out of range, the slot is just not taken
+            self.secondary_oam[self.secondary_oam_count * 4 + self.evaluation_byte] = data;
+            if self.evaluation_byte == 0 && !in_range {
+                address.wrapping_add(4)
+            } else {
+                if self.evaluation_byte == 0 && self.current_cycle == 66 {
+                    // first sprite checked on this line
+                    self.secondary_oam_has_zero = true;
+                }
+                self.evaluation_byte += 1;
+                if self.evaluation_byte == 4 {
+                    self.evaluation_byte = 0;
+                    self.secondary_oam_count += 1;
+                }
+                address.wrapping_add(1)
             }
+        } else if in_range {
+            // 9th sprite on this line
+            self.status.insert(Status::SPRITE_OVERFLOW);
+            self.is_evaluation_done = true;
+            address.wrapping_add(4)
+        } else {
+            // hardware bug: m is also incremented, so Y is compared with tile, attribute or X
+            (address.wrapping_add(4) & 0xFC) | (address.wrapping_add(1) & 0x03)
+        };
+
+        if next < address {
+            // n wrapped, all sprites are checked
+            self.is_evaluation_done = true;
+        }
+        self.oam_address = next;
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.control.sprite_size_16() {
+            16
+        } else {
+            8
+        }
+    }
 
-            self.fetched_sprites.push(sprite);
+    // empty slots fetch tile $FF, which is visible to mappers watching A12
+    fn fetch_sprite_pattern(&mut self, slot: usize, is_high: bool) {
+        let addr = self.sprite_pattern_address(slot) + if is_high { 8 } else { 0 };
+        let data = self.vram.read_internal(addr);
+        if slot >= self.secondary_oam_count {
+            return;
+        }
 
-            if self.fetched_sprites.len() == 8 {
-                return;
-            }
+        if !is_high {
+            let entry = &self.secondary_oam[(slot * 4)..(slot * 4 + 4)];
+            self.fetched_sprites.push(Sprite {
+                x: entry[3] as u16,
+                pattern_low: data,
+                pattern_high: 0,
+                attribute: Attribute::new(entry[2]),
+                is_zero: slot == 0 && self.secondary_oam_has_zero,
+            });
+        } else if let Some(sprite) = self.fetched_sprites.last_mut() {
+            sprite.pattern_high = data;
+        }
+    }
+
+    fn sprite_pattern_address(&self, slot: usize) -> u16 {
+        let (y, tile, attribute) = if slot < self.secondary_oam_count {
+            let entry = &self.secondary_oam[(slot * 4)..(slot * 4 + 4)];
+            (entry[0] as i16, entry[1] as u16, Attribute::new(entry[2]))
+        } else {
+            (self.current_line, 0xFF, Attribute::new(0))
+        };
+        let height = self.sprite_height();
+        let mut row = ((self.current_line - y) & (height - 1)) as u16;
+        if attribute.is_frip_vertically() {
+            // flipping 8x16 sprite also swaps top and bottom tiles
+            row = (height as u16) - 1 - row;
+        }
+
+        if self.control.sprite_size_16() {
+            // 8x16 ignores $2000 and selects pattern table by bit 0 of tile index
+            let bank = (tile & 0x01) * 0x1000;
+            bank + ((tile & 0xFE) + row / 8) * 16 + (row % 8)
+        } else {
+            self.control.sprite_pattern_addr() + tile * 16 + row
         }
     }
 
     #[inline(never)]
     fn process_pixel(&mut self) {
-        let (x,y) = self.current_point();
+        let (x, y) = (self.current_cycle as u16 - 1, self.current_line as u16);
 
         info!("process_pixel {},{}, ctrl:{:x}", x, y, self.control);
 
-        let mut palette_index = self.fetched_background.get_palette_index(x & 0x07, y & 0x07, &mut self.vram);
+        let is_background_opaque = self.is_background_visible(x)
+            && self.background.color_index(self.fine_x) != 0;
 
-        for sprite in self.fetched_sprites.iter() {
-            if sprite.in_bounding_x(x) {
-                palette_index = sprite.get_palette_index((x - sprite.x) % 8,
-                                                         (y - sprite.y) % 8,
-                                                         &mut self.vram);
-                break;
+        // lowest OAM index with opaque pixel wins, even if it is behind background
+        let sprite = if self.is_sprite_visible(x) {
+            self.fetched_sprites.iter().find(|sprite| {
+                sprite.in_bounding_x(x) && sprite.color_index(x - sprite.x) != 0
+            })
+        } else {
+            None
+        };
+
+        // hit never happens at x=255, clipped left column is already transparent
+        if let Some(sprite) = sprite {
+            if sprite.is_zero && is_background_opaque && x != 255 {
+                self.status.insert(Status::SPRITE_ZERO_HIT);
             }
         }
 
+        let palette_index = match sprite {
+            Some(sprite) if !is_background_opaque || !sprite.attribute.is_behind_background() => {
+                sprite.get_palette_index(x - sprite.x, &mut self.vram)
+            }
+            _ if is_background_opaque => {
+                let tile_color = self.background.palette(self.fine_x) << 2 | self.background.color_index(self.fine_x);
+                self.vram.read_internal(PALETTE_BASE_ADDR + tile_color as u16) & 0x3f
+            }
+            _ => self.vram.read_internal(PALETTE_BASE_ADDR) & 0x3f, // backdrop
+        };
+
         info!("palette_index {}", palette_index);
-        if palette_index != 0 {
-            self.put_pixel(palette_index, x, y);
-        }
+        self.put_pixel(self.mask.apply_greyscale(palette_index), x, y);
+    }
+
+    fn is_background_visible(&self, x: u16) -> bool {
+        self.mask.contains(Mask::SHOW_BACKGROUND)
+            && (x >= 8 || self.mask.contains(Mask::SHOW_BACKGROUND_LEFTMOST))
+    }
+
+    fn is_sprite_visible(&self, x: u16) -> bool {
+        self.mask.contains(Mask::SHOW_SPRITE)
+            && (x >= 8 || self.mask.contains(Mask::SHOW_SPRITE_LEFTMOST))
     }
 
     #[inline(always)]
     fn put_pixel(&mut self, palette_index: u8, x: u16, y: u16) {
-        self.output_frame[(x + y * SCREEN_WIDTH as u16) as usize] = palette_index;
+        let index = (x + y * SCREEN_WIDTH as u16) as usize;
+        self.output_frame[index] = palette_index;
+        self.output_emphasis[index] = self.mask.emphasis();
     }
 
 
     pub fn read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let result = match addr {
             0x2002 => {
-                // PPU_STATUS
-                self.status.bits()
+                // PPU_STATUS, low 5 bits are open bus
+                let result = self.status.bits() | (self.io_latch & 0x1F);
+                self.status.remove(Status::VBLANK);
+                self.write_toggle = false;
+                result
             }
             0x2004 => {
                 // OAM_DATA
-                self.oam_ram[self.oam_address as usize]
+                let is_evaluating = self.is_rendering_enabled() && self.current_line != PRE_RENDER_LINE
+                    && self.current_line < SCREEN_HEIGHT as i16;
+                if is_evaluating && 0 < self.current_cycle && self.current_cycle <= 64 {
+                    // secondary OAM is being cleared
+                    0xFF
+                } else if is_evaluating && 64 < self.current_cycle && self.current_cycle <= 256 {
+                    self.oam_latch
+                } else if (self.oam_address & 0x03) == 2 {
+                    // unimplemented bits of attribute
+                    self.oam_ram[self.oam_address as usize] & 0xE3
+                } else {
+                    self.oam_ram[self.oam_address as usize]
+                }
             }
             0x2007 => {
-                // PPU_DATA
-                let address = self.vram.get_addr();
-                let result = self.vram.read(address);
-                let inc = self.control.nametable_increment_value();
-                self.vram.increment_addr(inc);
+                // PPU_DATA, delayed by internal read buffer except palette
+                let address = self.vram_address & 0x3FFF;
+                let mut result = self.vram.read_with_buffer(address);
+                if address >= PALETTE_BASE_ADDR {
+                    // palette is 6bit, high 2 bits are open bus
+                    result = (result & 0x3F) | (self.io_latch & 0xC0);
+                }
+                self.increment_vram_address();
                 result
             }
-            _ => panic!("PPU read error:#{:x}", addr),
-        }
+            _ => {
+                // write only register, returns last value on the bus
+                info!("PPU read write-only register:#{:x}", addr);
+                self.io_latch
+            }
+        };
+        self.io_latch = result;
+        result
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
+        if addr != 0x4014 {
+            self.io_latch = data;
+        }
         match addr {
             0x2000 => {
                 // PPU_CTRL
                 self.control = Control::from_bits_truncate(data);
+                // t: ...GH.. ........ <- d: ......GH
+                self.temp_vram_address = (self.temp_vram_address & !0x0C00) | ((data as u16 & 0x03) << 10);
             }
             0x2001 => {
                 // PPU_MASK
                 self.mask = Mask::from_bits_truncate(data);
             }
+            0x2002 => {
+                // PPU_STATUS is read only
+            }
             0x2003 => {
                 // OAM_ADDRESS
                 self.oam_address = data;
             }
             0x2004 => {
                 // OAM_DATA
-                self.oam_ram[self.oam_address as usize] = data;
-                self.oam_address = self.oam_address.wrapping_add(1);
+                if self.is_rendering_enabled() && self.current_line < SCREEN_HEIGHT as i16 {
+                    // write is ignored while rendering, but address is bumped(high 6 bits)
+                    self.oam_address = self.oam_address.wrapping_add(4);
+                } else {
+                    self.oam_ram[self.oam_address as usize] = data;
+                    self.oam_address = self.oam_address.wrapping_add(1);
+                }
             }
             0x2005 => {
                 // PPU_SCROLL
-                self.scroll_position.insert(0, data);
-                self.scroll_position.truncate(2);
+                let data = data as u16;
+                if !self.write_toggle {
+                    // t: ....... ...ABCDE <- d: ABCDE...
+                    self.temp_vram_address = (self.temp_vram_address & !0x001F) | (data >> 3);
+                    self.fine_x = (data & 0x07) as u8;
+                } else {
+                    // t: FGH..AB CDE..... <- d: ABCDEFGH
+                    self.temp_vram_address = (self.temp_vram_address & !0x73E0)
+                        | ((data & 0x07) << 12)
+                        | ((data & 0xF8) << 2);
+                }
+                self.write_toggle = !self.write_toggle;
             }
             0x2006 => {
                 // PPU_ADDRESS
-                self.vram.set_addr(data);
+                let data = data as u16;
+                if !self.write_toggle {
+                    // t: .CDEFGH ........ <- d: ..CDEFGH, bit 14 is cleared
+                    self.temp_vram_address = (self.temp_vram_address & 0x00FF) | ((data & 0x3F) << 8);
+                } else {
+                    // t: ....... ABCDEFGH <- d: ABCDEFGH, then v = t
+                    self.temp_vram_address = (self.temp_vram_address & 0xFF00) | data;
+                    self.vram_address = self.temp_vram_address;
+                }
+                self.write_toggle = !self.write_toggle;
             }
             0x2007 => {
                 // PPU_DATA
-                let mut address = self.vram.get_addr();
-                self.vram.write(address, data);
-                let inc = self.control.nametable_increment_value();
-                self.vram.increment_addr(inc);
+                self.vram.write(self.vram_address & 0x3FFF, data);
+                self.increment_vram_address();
             }
             0x4014 => {
                 // OAM_DMA
                 let source = (data as u16) << 8;
                 // push task, because cant borrow mbc here
-                self.tasks.push(Box::new(OamDmaTask::new(source)));
+                self.tasks.push(OamDmaTask::new(source));
             }
             _ => panic!("PPU write error:#{:x},#{:x}", addr, data),
         }
@@ -510,35 +913,6 @@ impl Ppu {
     }
 }
 
-#[derive(Debug)]
-struct Pattern {
-    data: Vec<u8>,
-}
-
-impl Pattern {
-    fn new(data: Vec<u8>) -> Self {
-        Pattern {
-            data: data
-        }
-    }
-
-    pub fn color_index(&self, x: u8, y: u8) -> u8 {
-        let low = self.data[y as usize] << x & 0x80;
-        let high = self.data[8 + y as usize] << x & 0x80;
-        (low >> 7 | high >> 6) & 0x03
-    }
-
-    pub fn width(&self) -> u16 {
-        // TODO: 8x16 sprite
-        8
-    }
-
-    pub fn height(&self) -> u16 {
-        // TODO: 8x16 sprite
-        8
-    }
-}
-
 #[derive(Debug)]
 struct Attribute {
     attribute: u8,
@@ -549,17 +923,7 @@ impl Attribute {
         Attribute { attribute: attr }
     }
 
-    pub fn table_color_for_background(&self, pattern_x: u16, pattern_y: u16) -> u8 {
-        let attr_table_color = match (pattern_x & 0x03 < 2, pattern_y & 0x03 < 2) {
-            (true, true) => self.attribute & 0x03,
-            (false, true) => (self.attribute >> 2) & 0x03,
-            (true, false) => (self.attribute >> 4) & 0x03,
-            (false, false) => (self.attribute >> 6) & 0x03,
-        };
-        attr_table_color << 2
-    }
-
-    pub fn table_color_for_sprite(&self, pattern_x: u16, pattern_y: u16) -> u8 {
+    pub fn table_color_for_sprite(&self) -> u8 {
         (self.attribute & 0x03) << 2
     }
 
@@ -570,95 +934,142 @@ impl Attribute {
     pub fn is_frip_vertically(&self) -> bool {
         (self.attribute & 0x80) != 0
     }
+
+    pub fn is_behind_background(&self) -> bool {
+        (self.attribute & 0x20) != 0
+    }
 }
 
 // ==
+// fetched bytes wait in latches, then are loaded into low byte of 16bit shift registers.
+// high byte holds the tile being drawn
 #[derive(Debug)]
-struct BackgroundImage {
-    pattern_addr: u16, // debug
-    attribute_addr: u16, // debug
-    pattern: Pattern,
-    attribute: Attribute,
+struct BackgroundPipeline {
+    next_tile_index: u8,
+    next_attribute: u8, // 2bit palette number
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+
+    pattern_low: u16,
+    pattern_high: u16,
+    attribute_low: u16,
+    attribute_high: u16,
 }
 
-impl BackgroundImage {
-    fn from_vram_address(pattern_address: u16,
-                         attribute_address: u16,
-                         vram: &mut Vram) -> Self {
-        let pattern_memory = vram.read_vram_range(pattern_address, pattern_address + 16);
-        let attribute = vram.read(attribute_address);
-
-        BackgroundImage {
-            pattern_addr: pattern_address,
-            attribute_addr: attribute_address,
-            pattern: Pattern::new(pattern_memory),
-            attribute: Attribute::new(attribute),
+impl BackgroundPipeline {
+    fn new() -> Self {
+        BackgroundPipeline {
+            next_tile_index: 0,
+            next_attribute: 0,
+            next_pattern_low: 0,
+            next_pattern_high: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            attribute_low: 0,
+            attribute_high: 0,
         }
     }
 
-    fn empty() -> Self {
-        let pattern_memory = vec![0; 16];
-        let attribute = 0;
+    fn load(&mut self) {
+        self.pattern_low = (self.pattern_low & 0xFF00) | self.next_pattern_low as u16;
+        self.pattern_high = (self.pattern_high & 0xFF00) | self.next_pattern_high as u16;
+        // attribute is same for all 8 pixels of the tile
+        let low = if (self.next_attribute & 0x01) != 0 { 0xFF } else { 0x00 };
+        let high = if (self.next_attribute & 0x02) != 0 { 0xFF } else { 0x00 };
+        self.attribute_low = (self.attribute_low & 0xFF00) | low;
+        self.attribute_high = (self.attribute_high & 0xFF00) | high;
+    }
 
-        BackgroundImage {
-            pattern_addr: 0,
-            attribute_addr: 0,
-            pattern: Pattern::new(pattern_memory),
-            attribute: Attribute::new(attribute),
-        }
+    fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.attribute_low <<= 1;
+        self.attribute_high <<= 1;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.next_tile_index);
+        state.write_u8(self.next_attribute);
+        state.write_u8(self.next_pattern_low);
+        state.write_u8(self.next_pattern_high);
+        state.write_u16(self.pattern_low);
+        state.write_u16(self.pattern_high);
+        state.write_u16(self.attribute_low);
+        state.write_u16(self.attribute_high);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.next_tile_index = state.read_u8()?;
+        self.next_attribute = state.read_u8()?;
+        self.next_pattern_low = state.read_u8()?;
+        self.next_pattern_high = state.read_u8()?;
+        self.pattern_low = state.read_u16()?;
+        self.pattern_high = state.read_u16()?;
+        self.attribute_low = state.read_u16()?;
+        self.attribute_high = state.read_u16()?;
+        Ok(())
+    }
+
+    fn bit(register: u16, fine_x: u8) -> u8 {
+        ((register << fine_x) >> 15) as u8
     }
 
-    fn get_palette_index(&self, x: u16, y: u16, vram: &mut Vram) -> u8 {
-        let color_index = self.pattern.color_index((x & 0x07) as u8, (y & 0x07) as u8);
-        let tile_color = self.attribute.table_color_for_background(x / 8 % 32, y / 8 % 32) | color_index;
-        let palette_addr = PALETTE_BASE_ADDR + tile_color as u16;
-        let palette_index = vram.read_internal(palette_addr) & 0x3f;
-        palette_index
+    // 0 is transparent
+    fn color_index(&self, fine_x: u8) -> u8 {
+        BackgroundPipeline::bit(self.pattern_high, fine_x) << 1 | BackgroundPipeline::bit(self.pattern_low, fine_x)
+    }
+
+    fn palette(&self, fine_x: u8) -> u8 {
+        BackgroundPipeline::bit(self.attribute_high, fine_x) << 1 | BackgroundPipeline::bit(self.attribute_low, fine_x)
     }
 }
 
+// one row of a sprite on the line being drawn
 #[derive(Debug)]
 struct Sprite {
-    y: u16,
     x: u16,
-    pattern: Pattern,
+    pattern_low: u8,
+    pattern_high: u8,
     attribute: Attribute,
+    is_zero: bool, // sprite 0, for sprite 0 hit
 }
 
 impl Sprite {
-    fn from_oam(oam: &[u8], vram: &mut Vram, sprite_pattern_base_addr: u16) -> Self {
-        let head_address = (oam[1] as u16) * 2 * 8 + sprite_pattern_base_addr;
-        let pattern_memory = vram.read_vram_range(head_address, head_address + 16);
-
-        Sprite{
-            y: oam[0] as u16 + 1,
-            x: oam[3] as u16,
-            pattern: Pattern::new(pattern_memory),
-            attribute: Attribute::new(oam[2]),
-        }
+    // x: position in the sprite, 0 is transparent
+    fn color_index(&self, x: u16) -> u8 {
+        let shift = if self.attribute.is_frip_horizontally() { x } else { 7 - x };
+        let low = (self.pattern_low >> shift) & 0x01;
+        let high = (self.pattern_high >> shift) & 0x01;
+        high << 1 | low
     }
 
-    fn get_palette_index(&self, mut x: u16, mut y: u16, vram: &mut Vram) -> u8 {
-        if self.attribute.is_frip_vertically() {
-            y = 7 - y;
-        }
-        if self.attribute.is_frip_horizontally() {
-            x = 7 - x;
-        }
-
-        let color_index = self.pattern.color_index((x & 0x07) as u8, (y & 0x07) as u8);
-        let tile_color = self.attribute.table_color_for_sprite(x, y) | color_index;
+    fn get_palette_index(&self, x: u16, vram: &mut Vram) -> u8 {
+        let color_index = self.color_index(x);
+        let tile_color = self.attribute.table_color_for_sprite() | color_index;
         let palette_addr = PALETTE_SPRITE_ADDR + tile_color as u16;
-        let palette_index = vram.read_internal(palette_addr) & 0x3f;
-        palette_index
+        vram.read_internal(palette_addr) & 0x3f
     }
 
     fn in_bounding_x(&self, x: u16) -> bool {
         (self.x <= x) && (x < self.x + 8)
     }
 
-    fn in_bounding_y(&self, y: u16) -> bool {
-        (self.y <= y) && (y < self.y + 8)
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.x);
+        state.write_u8(self.pattern_low);
+        state.write_u8(self.pattern_high);
+        state.write_u8(self.attribute.attribute);
+        state.write_bool(self.is_zero);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Sprite {
+            x: state.read_u16()?,
+            pattern_low: state.read_u8()?,
+            pattern_high: state.read_u8()?,
+            attribute: Attribute::new(state.read_u8()?),
+            is_zero: state.read_bool()?,
+        })
     }
 }
 
@@ -684,9 +1095,11 @@ impl OamDmaTask {
         let mbc = ppu.mbc.upgrade().unwrap();
         let mbc = mbc.borrow();
         for i in 0..0x0100u16 {
-            let s = (self.source + i) as u16;
+            let s = self.source + i;
             let v = mbc.read(s);
-            ppu.oam_ram[i as usize] = v;
+            // DMA starts writing at OAMADDR
+            let index = ppu.oam_address.wrapping_add(i as u8);
+            ppu.oam_ram[index as usize] = v;
             info!(
                 "oam_ram[0x{:04x}] = mapper.read(0x{:04x}) = {:02x}",
                 i, s, v
@@ -694,3 +1107,420 @@ impl OamDmaTask {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+
+    fn new_ppu() -> Ppu {
+        Ppu::new(Rc::new(RefCell::new(mapper::empty())))
+    }
+
+    #[test]
+    fn scroll_and_ctrl_writes_fill_t_and_fine_x() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2000, 0x03);
+        ppu.write(0x2005, 0x7D); // coarse X = 15, fine X = 5
+        ppu.write(0x2005, 0x5E); // coarse Y = 11, fine Y = 6
+        assert_eq!(ppu.temp_vram_address, 0x6C00 | (11 << 5) | 15);
+        assert_eq!(ppu.fine_x, 5);
+        assert_eq!(ppu.vram_address, 0);
+    }
+
+    #[test]
+    fn second_address_write_copies_t_to_v() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2006, 0xFF); // bit 14 is cleared
+        assert_eq!(ppu.vram_address, 0);
+        ppu.write(0x2006, 0x21);
+        assert_eq!(ppu.vram_address, 0x3F21);
+    }
+
+    #[test]
+    fn status_read_resets_write_toggle() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2006, 0x12);
+        ppu.read(0x2002);
+        ppu.write(0x2006, 0x23);
+        ppu.write(0x2006, 0x45);
+        assert_eq!(ppu.vram_address, 0x2345);
+    }
+
+    #[test]
+    fn coarse_x_wraps_to_next_nametable() {
+        let mut ppu = new_ppu();
+        ppu.vram_address = 0x001F;
+        ppu.increment_coarse_x();
+        assert_eq!(ppu.vram_address, 0x0400);
+    }
+
+    // write through $2007, then read back(first read returns the stale buffer)
+    fn write_and_read_ppu_data(ppu: &mut Ppu, addr: u16, data: u8) -> u8 {
+        ppu.write(0x2006, (addr >> 8) as u8);
+        ppu.write(0x2006, addr as u8);
+        ppu.write(0x2007, data);
+        ppu.write(0x2006, (addr >> 8) as u8);
+        ppu.write(0x2006, addr as u8);
+        ppu.read(0x2007);
+        ppu.read(0x2007)
+    }
+
+    #[test]
+    fn chr_ram_stores_ppu_data_writes() {
+        let mapper = mapper::new_mapper(mapper::test_rom(0, 0x4000, 0));
+        let mut ppu = Ppu::new(Rc::new(RefCell::new(mapper)));
+        assert_eq!(write_and_read_ppu_data(&mut ppu, 0x1234, 0x5A), 0x5A);
+    }
+
+    #[test]
+    fn chr_rom_ignores_ppu_data_writes() {
+        let mapper = mapper::new_mapper(mapper::test_rom(0, 0x4000, 0x2000));
+        let mut ppu = Ppu::new(Rc::new(RefCell::new(mapper)));
+        // test_rom fills each 1KB of CHR with its index
+        assert_eq!(write_and_read_ppu_data(&mut ppu, 0x1234, 0x5A), 0x04);
+    }
+
+    #[test]
+    fn powers_up_at_line_0() {
+        assert_eq!(new_ppu().position(), (0, 0));
+    }
+
+    #[test]
+    fn y_increment_wraps_at_row_29_and_31() {
+        let mut ppu = new_ppu();
+        ppu.vram_address = 0x7000 | (29 << 5);
+        ppu.increment_y();
+        assert_eq!(ppu.vram_address, 0x0800);
+
+        ppu.vram_address = 0x7000 | (31 << 5);
+        ppu.increment_y();
+        assert_eq!(ppu.vram_address, 0x0000);
+    }
+
+    // sprite part of dot first-last of current line
+    fn run_sprite_cycles(ppu: &mut Ppu, first: i16, last: i16) {
+        for cycle in first..(last + 1) {
+            ppu.current_cycle = cycle;
+            ppu.process_sprite_cycle();
+        }
+    }
+
+    #[test]
+    fn ninth_sprite_on_line_sets_overflow() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2001, 0x18);
+        for i in 0..9 {
+            ppu.oam_ram[i * 4] = 10;
+        }
+        ppu.current_line = 11;
+        run_sprite_cycles(&mut ppu, 1, 320);
+        assert_eq!(ppu.secondary_oam_count, 8);
+        assert!(ppu.secondary_oam_has_zero);
+        assert!(ppu.status.contains(Status::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn overflow_check_after_8_sprites_is_misaligned() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2001, 0x18);
+        for i in 0..64 {
+            ppu.oam_ram[i * 4] = 0xF0;
+        }
+        for i in 0..8 {
+            ppu.oam_ram[i * 4] = 10;
+        }
+        // after 9th sprite misses, 10th sprite's tile index is compared instead of Y
+        ppu.oam_ram[9 * 4] = 10;
+        ppu.current_line = 11;
+        run_sprite_cycles(&mut ppu, 1, 320);
+        assert!(!ppu.status.contains(Status::SPRITE_OVERFLOW));
+
+        // tile index looks like in range
+        ppu.oam_ram[9 * 4 + 1] = 10;
+        run_sprite_cycles(&mut ppu, 1, 320);
+        assert!(ppu.status.contains(Status::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn evaluation_copies_one_byte_per_two_dots() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2001, 0x18);
+        for i in 0..8 {
+            ppu.oam_ram[i * 4] = 10;
+        }
+        ppu.current_line = 11;
+        // dot 66-96: 16 writes, 4 sprites
+        run_sprite_cycles(&mut ppu, 1, 96);
+        assert_eq!(ppu.secondary_oam_count, 4);
+        run_sprite_cycles(&mut ppu, 97, 256);
+        assert_eq!(ppu.secondary_oam_count, 8);
+    }
+
+    #[test]
+    fn oam_address_write_during_evaluation_skips_sprites() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2001, 0x18);
+        for i in 0..64 {
+            ppu.oam_ram[i * 4] = if i < 5 { 10 } else { 0xF0 };
+            ppu.oam_ram[i * 4 + 1] = i as u8; // tile index
+        }
+        ppu.current_line = 11;
+        // sprite 0 is copied by dot 72, then jump to sprite 3
+        run_sprite_cycles(&mut ppu, 1, 72);
+        ppu.write(0x2003, 0x0C);
+        run_sprite_cycles(&mut ppu, 73, 256);
+        assert_eq!(ppu.secondary_oam_count, 3);
+        let tiles: Vec<u8> = (0..3).map(|slot| ppu.secondary_oam[slot * 4 + 1]).collect();
+        assert_eq!(tiles, vec![0, 3, 4]);
+    }
+
+    #[test]
+    fn oam_data_read_during_evaluation_returns_evaluated_byte() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2001, 0x18);
+        ppu.oam_ram[0..4].copy_from_slice(&[10, 0x23, 0x01, 0x40]);
+        ppu.current_line = 11;
+        run_sprite_cycles(&mut ppu, 1, 67);
+        assert_eq!(ppu.read(0x2004), 0x23);
+        run_sprite_cycles(&mut ppu, 68, 71);
+        assert_eq!(ppu.read(0x2004), 0x40);
+    }
+
+    #[test]
+    fn pre_render_line_clears_sprite_flags() {
+        let mut ppu = new_ppu();
+        ppu.status.insert(Status::SPRITE_ZERO_HIT | Status::SPRITE_OVERFLOW);
+        ppu.current_line = PRE_RENDER_LINE;
+        ppu.current_cycle = 1;
+        ppu.process_cycle();
+        assert!(!ppu.status.intersects(Status::SPRITE_ZERO_HIT | Status::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn tall_sprite_flips_across_both_tiles() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2000, 0x20);
+        ppu.secondary_oam[0..4].copy_from_slice(&[10, 0x23, 0x80, 0]);
+        ppu.secondary_oam_count = 1;
+        // top row of flipped sprite is the last row of bottom tile, from $1000
+        ppu.current_line = 10;
+        assert_eq!(ppu.sprite_pattern_address(0), 0x1000 + 0x23 * 16 + 7);
+        ppu.current_line = 25;
+        assert_eq!(ppu.sprite_pattern_address(0), 0x1000 + 0x22 * 16);
+    }
+
+    #[test]
+    fn evaluated_sprites_are_fetched_for_next_line() {
+        let mut ppu = new_ppu();
+        ppu.vram.write(0x0015, 0xA5); // tile 1, row 5
+        ppu.oam_ram[4..8].copy_from_slice(&[20, 0x01, 0x00, 40]);
+        ppu.write(0x2001, 0x18);
+        ppu.current_line = 25;
+        for cycle in 1..321 {
+            ppu.current_cycle = cycle;
+            ppu.process_sprite_cycle();
+        }
+        assert_eq!(ppu.fetched_sprites.len(), 1);
+        assert_eq!(ppu.fetched_sprites[0].x, 40);
+        assert_eq!(ppu.fetched_sprites[0].pattern_low, 0xA5);
+        assert!(!ppu.fetched_sprites[0].is_zero);
+    }
+
+    fn composite(ppu: &mut Ppu, background_opaque: bool, sprite_attribute: u8) -> u8 {
+        ppu.write(0x2001, 0x1E);
+        ppu.vram.write(0x3F00, 0x0F);
+        ppu.vram.write(0x3F01, 0x11);
+        ppu.vram.write(0x3F11, 0x22);
+        ppu.background = BackgroundPipeline::new();
+        if background_opaque {
+            ppu.background.pattern_low = 0xFFFF;
+        }
+        ppu.fetched_sprites = vec![Sprite {
+            x: 0,
+            pattern_low: 0xFF,
+            pattern_high: 0x00,
+            attribute: Attribute::new(sprite_attribute),
+            is_zero: false,
+        }];
+        ppu.current_line = 0;
+        ppu.current_cycle = 1;
+        ppu.process_pixel();
+        ppu.output_frame[0]
+    }
+
+    #[test]
+    fn sprite_behind_background_shows_through_transparent_background() {
+        let mut ppu = new_ppu();
+        assert_eq!(composite(&mut ppu, false, 0x20), 0x22);
+        assert_eq!(composite(&mut ppu, true, 0x20), 0x11);
+        assert_eq!(composite(&mut ppu, true, 0x00), 0x22);
+    }
+
+    #[test]
+    fn transparent_pixels_show_backdrop() {
+        let mut ppu = new_ppu();
+        composite(&mut ppu, false, 0x00);
+        ppu.fetched_sprites.clear();
+        ppu.process_pixel();
+        assert_eq!(ppu.output_frame[0], 0x0F);
+    }
+
+    #[test]
+    fn hidden_layers_and_left_column_show_backdrop() {
+        let mut ppu = new_ppu();
+        composite(&mut ppu, true, 0x00);
+        ppu.write(0x2001, 0x18); // clip left 8 pixels of both layers
+        ppu.process_pixel();
+        assert_eq!(ppu.output_frame[0], 0x0F);
+
+        ppu.write(0x2001, 0x0A); // background only
+        ppu.process_pixel();
+        assert_eq!(ppu.output_frame[0], 0x11);
+    }
+
+    // opaque sprite 0 over opaque background at dot x+1
+    fn sprite_zero_hit_at(ppu: &mut Ppu, x: u16) -> bool {
+        composite(ppu, true, 0x00);
+        ppu.fetched_sprites[0].x = x & 0xF8;
+        ppu.fetched_sprites[0].is_zero = true;
+        ppu.status.remove(Status::SPRITE_ZERO_HIT);
+        ppu.current_cycle = x as i16 + 1;
+        ppu.process_pixel();
+        ppu.status.contains(Status::SPRITE_ZERO_HIT)
+    }
+
+    #[test]
+    fn opaque_sprite_zero_over_background_sets_hit() {
+        let mut ppu = new_ppu();
+        assert!(sprite_zero_hit_at(&mut ppu, 0));
+        assert!(sprite_zero_hit_at(&mut ppu, 254));
+    }
+
+    #[test]
+    fn sprite_zero_hit_never_happens_at_x_255() {
+        let mut ppu = new_ppu();
+        assert!(!sprite_zero_hit_at(&mut ppu, 255));
+    }
+
+    #[test]
+    fn clipped_left_column_has_no_sprite_zero_hit() {
+        let mut ppu = new_ppu();
+        for &mask in &[0x18, 0x1A, 0x1C] {
+            sprite_zero_hit_at(&mut ppu, 0);
+            ppu.write(0x2001, mask);
+            ppu.status.remove(Status::SPRITE_ZERO_HIT);
+            ppu.process_pixel();
+            assert!(!ppu.status.contains(Status::SPRITE_ZERO_HIT), "mask:{:02x}", mask);
+        }
+        // first pixel after the clipped column
+        sprite_zero_hit_at(&mut ppu, 8);
+        ppu.write(0x2001, 0x18);
+        ppu.status.remove(Status::SPRITE_ZERO_HIT);
+        ppu.process_pixel();
+        assert!(ppu.status.contains(Status::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn greyscale_masks_palette_index() {
+        let mut ppu = new_ppu();
+        composite(&mut ppu, true, 0x20);
+        ppu.write(0x2001, 0x1F);
+        ppu.process_pixel();
+        assert_eq!(ppu.output_frame[0], 0x10);
+    }
+
+    #[test]
+    fn emphasis_darkens_other_channels() {
+        assert_eq!(emphasize([0xFF, 0xFF, 0xFF], 0), [0xFF, 0xFF, 0xFF]);
+        let color = emphasize([0xFF, 0xFF, 0xFF], Mask::EMP_RED.bits());
+        assert!(color[0] < 0xFF && color[1] < 0xFF);
+        assert_eq!(color[2], 0xFF);
+    }
+
+    #[test]
+    fn data_read_is_delayed_by_buffer() {
+        let mut ppu = new_ppu();
+        ppu.vram.write(0x2000, 0x12);
+        ppu.vram.write(0x2001, 0x34);
+        ppu.write(0x2006, 0x20);
+        ppu.write(0x2006, 0x00);
+        ppu.read(0x2007); // dummy read
+        assert_eq!(ppu.read(0x2007), 0x12);
+        assert_eq!(ppu.read(0x2007), 0x34);
+    }
+
+    #[test]
+    fn palette_read_is_not_buffered() {
+        let mut ppu = new_ppu();
+        ppu.vram.write(0x3F01, 0x2A);
+        ppu.write(0x2006, 0x3F);
+        ppu.write(0x2006, 0x01);
+        assert_eq!(ppu.read(0x2007) & 0x3F, 0x2A);
+    }
+
+    #[test]
+    fn status_read_clears_vblank() {
+        let mut ppu = new_ppu();
+        ppu.status.insert(Status::VBLANK);
+        assert!((ppu.read(0x2002) & 0x80) != 0);
+        assert!((ppu.read(0x2002) & 0x80) == 0);
+    }
+
+    #[test]
+    fn write_only_register_reads_open_bus() {
+        let mut ppu = new_ppu();
+        ppu.write(0x2003, 0x5A);
+        assert_eq!(ppu.read(0x2000), 0x5A);
+        assert_eq!(ppu.read(0x2002) & 0x1F, 0x1A);
+    }
+
+    #[test]
+    fn prefetched_tiles_are_drawn_with_fine_x() {
+        let mut ppu = new_ppu();
+        // tile 0 is blank, tile 1 is solid, nametable is 0, 1, ...
+        for row in 0..8 {
+            ppu.vram.write(0x0010 + row, 0xFF);
+        }
+        ppu.vram.write(0x2001, 0x01);
+        ppu.write(0x2001, 0x0A);
+        ppu.fine_x = 3;
+        ppu.current_line = PRE_RENDER_LINE;
+        for cycle in 321..338 {
+            ppu.current_cycle = cycle;
+            ppu.fetch_background();
+        }
+        // dot 1-5 draw pixel 3-7 of tile 0
+        for _ in 1..6 {
+            assert_eq!(ppu.background.color_index(ppu.fine_x), 0);
+            ppu.background.shift();
+        }
+        assert_eq!(ppu.background.color_index(ppu.fine_x), 1);
+    }
+
+    #[test]
+    fn load_state_rejects_out_of_range_values() {
+        let load = |ppu: &Ppu| {
+            let mut state = StateWriter::payload();
+            ppu.save_state(&mut state);
+            let data = state.into_bytes();
+            new_ppu().load_state(&mut StateReader::payload(&data))
+        };
+        let mut ppu = new_ppu();
+        ppu.current_line = SCANLINE_PER_SCREEN - 2;
+        ppu.current_cycle = CYCLE_PER_LINE - 1;
+        assert_eq!(load(&ppu), Ok(()));
+
+        let mut ppu = new_ppu();
+        ppu.fine_x = 16;
+        assert_eq!(load(&ppu), Err(StateError::InvalidValue("fine X scroll")));
+
+        let mut ppu = new_ppu();
+        ppu.current_line = SCANLINE_PER_SCREEN - 1;
+        assert_eq!(load(&ppu), Err(StateError::InvalidValue("scanline")));
+
+        let mut ppu = new_ppu();
+        ppu.current_cycle = CYCLE_PER_LINE;
+        assert_eq!(load(&ppu), Err(StateError::InvalidValue("dot")));
+    }
+}
@@ -1,7 +1,7 @@
+use nes::mapper::{Mapper, Mirroring};
+use nes::state::{StateError, StateReader, StateWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::ops::Range;
-use std::slice;
 use std::vec::Vec;
 
 const INITIAL_PALETTE_TABLE: [u8; 32] = [
@@ -13,22 +13,19 @@ struct NameTable {
     ram: Vec<u8>,
 }
 
-struct PatternTable {
-    ram: Vec<u8>,
-    is_writable: bool,
-}
-
 struct PaletteTable {
     ram: Vec<u8>,
 }
 
 pub struct Vram {
-    pattern_tables: Vec<PatternTable>,
-    name_tables: Vec<Rc<RefCell<Box<NameTable>>>>,
+    // pattern tables are provided by the cartridge(CHR ROM/RAM)
+    mapper: Rc<RefCell<Box<dyn Mapper>>>,
+    // 2KB internal vram and 2KB on four-screen cartridge, arranged to $2000-$2FFF by mirroring
+    name_tables: Vec<NameTable>,
     palette_tables: Vec<Rc<RefCell<Box<PaletteTable>>>>,
 
-    vram_write_addr: Vec<u8>,
     read_buffer: u8,
+    cycle: u64, // PPU cycle, for mapper's A12 watching
 }
 
 impl NameTable {
@@ -42,37 +39,12 @@ impl NameTable {
         self.ram[addr as usize]
     }
 
-    fn read_range(&self, range: Range<usize>, vec: &mut Vec<u8>) {
-        vec.copy_from_slice(&self.ram[range]);
-    }
-
     fn write(&mut self, addr: u16, data: u8) {
         info!("Vram::write {:04x},{:02x}", addr, data);
         self.ram[addr as usize] = data
     }
 }
 
-impl PatternTable {
-    fn new() -> Self {
-        PatternTable {
-            ram: vec![0x0u8; 0x1000],
-            is_writable: true,
-        }
-    }
-
-    fn read(&self, addr: u16) -> u8 {
-        self.ram[addr as usize]
-    }
-
-    fn read_range(&self, range: Range<usize>, vec: &mut Vec<u8>) {
-        vec.copy_from_slice(&self.ram[range]);
-    }
-
-    fn write(&mut self, addr: u16, data: u8) {
-        self.ram[addr as usize] = data
-    }
-}
-
 impl PaletteTable {
     fn new(initial: &[u8]) -> Self {
         PaletteTable {
@@ -85,10 +57,6 @@ impl PaletteTable {
         self.ram[addr as usize]
     }
 
-    fn read_range(&self, range: Range<usize>, vec: &mut Vec<u8>) {
-        vec.copy_from_slice(&self.ram[range]);
-    }
-
     fn write(&mut self, addr: u16, data: u8) {
         let addr = PaletteTable::normalize_addr(addr);
         self.ram[addr as usize] = data
@@ -97,7 +65,7 @@ impl PaletteTable {
     fn normalize_addr(addr: u16) -> u16 {
         // mirror 3F10/3F14/3F18/3F1C -> 3F00/3F14/3F18/3F1C
         let mut addr = addr & 0x001F;
-        if addr % 4 == 0 {
+        if addr.is_multiple_of(4) {
             addr &= !0x10;
         }
         addr
@@ -105,45 +73,24 @@ impl PaletteTable {
 }
 
 impl Vram {
-    pub fn new(is_horizontal: bool) -> Self {
-        let mut name_tables = Vec::new();
-
-        if is_horizontal {
-            let table0 = Rc::new(RefCell::new(Box::new(NameTable::new())));
-            let table2 = Rc::new(RefCell::new(Box::new(NameTable::new())));
-            name_tables.push(table0.clone());
-            name_tables.push(table0); // mirror of name table 0
-            name_tables.push(table2.clone());
-            name_tables.push(table2); // mirror of name table 2
-        } else {
-            let table0 = Rc::new(RefCell::new(Box::new(NameTable::new())));
-            let table1 = Rc::new(RefCell::new(Box::new(NameTable::new())));
-            name_tables.push(table0.clone());
-            name_tables.push(table1.clone());
-            name_tables.push(table0); // mirror of name table 0
-            name_tables.push(table1); // mirror of name table 1
-        }
-
-        let mut pattern_tables = Vec::new();
-        for _ in 0..2 {
-            pattern_tables.push(PatternTable::new());
-        }
+    pub fn new(mapper: Rc<RefCell<Box<dyn Mapper>>>) -> Self {
+        let name_tables = vec![NameTable::new(), NameTable::new(), NameTable::new(), NameTable::new()];
 
         let mut palette_tables = Vec::new();
         let table = Rc::new(RefCell::new(Box::new(PaletteTable::new(
             &INITIAL_PALETTE_TABLE,
         ))));
         // mirror of palette 3F00~3F1F (3F20)-(3FFF)
-        for index in 0..8 {
+        for _ in 0..8 {
             palette_tables.push(table.clone());
         }
 
         Vram {
-            pattern_tables: pattern_tables,
+            mapper: mapper,
             name_tables: name_tables,
             palette_tables: palette_tables,
-            vram_write_addr: vec![0, 0],
             read_buffer: 0x00,
+            cycle: 0,
         }
     }
 
@@ -167,15 +114,42 @@ impl Vram {
         result
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        for table in self.name_tables.iter() {
+            state.write_bytes(&table.ram);
+        }
+        // all palette mirrors share the same table
+        state.write_bytes(&self.palette_tables[0].borrow().ram);
+        state.write_u8(self.read_buffer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for table in self.name_tables.iter_mut() {
+            state.read_bytes_into(&mut table.ram)?;
+        }
+        state.read_bytes_into(&mut self.palette_tables[0].borrow_mut().ram)?;
+        self.read_buffer = state.read_u8()?;
+        Ok(())
+    }
+
+    pub fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    fn notify_address(&self, addr: u16) {
+        self.mapper.borrow_mut().ppu_address(addr, self.cycle);
+    }
+
     pub fn read_internal(&mut self, addr: u16) -> u8 {
         let result = match addr {
             0x0000...0x1FFF => {
-                let (index, target_addr) = Vram::calclate_patterntable_addr(addr);
-                self.pattern_tables[index].read(target_addr)
+                self.notify_address(addr);
+                self.mapper.borrow().read_chr(addr)
             }
             0x2000...0x3EFF => {
-                let (index, target_addr) = Vram::calclate_nametable_addr(addr);
-                self.name_tables[index].borrow().read(target_addr)
+                self.notify_address(addr);
+                let (index, target_addr) = self.calclate_nametable_addr(addr);
+                self.name_tables[index].read(target_addr)
             }
             0x3F00...0x3FFF => {
                 let (index, target_addr) = Vram::calclate_palettetable_addr(addr);
@@ -188,92 +162,18 @@ impl Vram {
         result
     }
 
-    // copy memory from vram
-    // dont check the memory type. (PaletteTable, PatternTable, NameTable)
-    fn read_internal_range(&mut self, range: Range<u16>, vec: &mut Vec<u8>) {
-        match range.start {
-            0x0000...0x1FFF => {
-                let (index, target_addr) = Vram::calclate_patterntable_addr(range.start);
-                let target_addr = target_addr as usize;
-                self.pattern_tables[index]
-                    .read_range(target_addr..(target_addr + range.count()), vec);
-            }
-            0x2000...0x3EFF => {
-                let (index, target_addr) = Vram::calclate_nametable_addr(range.start);
-                let target_addr = target_addr as usize;
-                self.name_tables[index]
-                    .borrow()
-                    .read_range(target_addr..(target_addr + range.count()), vec);
-            }
-            0x3F00...0x3FFF => {
-                let (index, target_addr) = Vram::calclate_palettetable_addr(range.start);
-                let target_addr = target_addr as usize;
-                // self.palette_tables[index].ram[target_addr..(target_addr+range.count())]
-                self.palette_tables[index]
-                    .borrow()
-                    .read_range(target_addr..(target_addr + range.count()), vec);
-            }
-            _ => {
-                panic!("cant read PPU:0x{:04x}", range.start);
-            }
-        };
-    }
-
-    // TODO:no copy
-    pub fn read_vram_range(&mut self, start: u16, end: u16) -> Vec<u8> {
-        let size = (end - start) as usize;
-        let mut v = Vec::with_capacity(size);
-        unsafe {
-            v.set_len(size);
-        }
-        info!("v:{:?}, v.len:{:?}", v, v.len());
-        self.read_internal_range(start..end, &mut v);
-        v
-    }
-
-    pub fn read(&mut self, addr: u16) -> u8 {
-        info!("Vram::read({:04x})", addr);
-        self.read_with_buffer(addr)
-    }
-
-    pub fn get_addr(&self) -> u16 {
-        let mut address = self.vram_write_addr[0] as u16;
-        address |= (self.vram_write_addr[1] as u16) << 8;
-        address
-    }
-
-    pub fn set_addr(&mut self, half_addr: u8) {
-        self.vram_write_addr.insert(0, half_addr);
-        self.vram_write_addr.truncate(2);
-        info!(
-            "PPU VRAM write addr : 0x{:02x}{:02x}",
-            self.vram_write_addr[1], self.vram_write_addr[0],
-        );
-    }
-
-    pub fn clear_addr(&mut self) {
-        self.vram_write_addr = vec![0, 0];
-    }
-
-    pub fn increment_addr(&mut self, value: u16) {
-        let address = self.get_addr().wrapping_add(value);
-        self.vram_write_addr[0] = (address & 0xFF) as u8;
-        self.vram_write_addr[1] = (address >> 8) as u8;
-    }
-
     pub fn write(&mut self, addr: u16, data: u8) {
         info!("Vram::write({:04x}, {:02x})", addr, data);
         match addr {
             0x0000...0x1FFF => {
-                let (index, target_addr) = Vram::calclate_patterntable_addr(addr);
-                self.pattern_tables[index].write(target_addr, data)
+                self.notify_address(addr);
+                self.mapper.borrow_mut().write_chr(addr, data)
             }
-            0x2000...0x3E00 => {
-                let (index, target_addr) = Vram::calclate_nametable_addr(addr);
+            0x2000...0x3EFF => {
+                self.notify_address(addr);
+                let (index, target_addr) = self.calclate_nametable_addr(addr);
                 info!("nametable[{:x}][{:x}]", index, target_addr);
-                self.name_tables[index]
-                    .borrow_mut()
-                    .write(target_addr, data)
+                self.name_tables[index].write(target_addr, data)
             }
             0x3F00...0x3FFF => {
                 let (index, target_addr) = Vram::calclate_palettetable_addr(addr);
@@ -287,16 +187,17 @@ impl Vram {
         }
     }
 
-    fn calclate_nametable_addr(addr: u16) -> (usize, u16) {
-        let index = (addr - 0x2000) / 0x0400;
+    // $3000-$3EFF is mirror of $2000-$2EFF
+    fn calclate_nametable_addr(&self, addr: u16) -> (usize, u16) {
+        let table = ((addr - 0x2000) & 0x0FFF) / 0x0400;
         let target_addr = (addr - 0x2000) % 0x0400;
-
-        (index as usize, target_addr)
-    }
-
-    fn calclate_patterntable_addr(addr: u16) -> (usize, u16) {
-        let index = addr / 0x1000;
-        let target_addr = addr % 0x1000;
+        let index = match self.mapper.borrow().mirroring() {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
 
         (index as usize, target_addr)
     }
@@ -308,3 +209,80 @@ impl Vram {
         (index as usize, target_addr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nes::mapper;
+    use nes::rom::Rom;
+
+    // NROM with the mirroring bits of flags 6
+    fn nrom_vram(flags6: u8) -> Vram {
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend(vec![0u8; 0x4000 + 0x2000]);
+        let mapper = mapper::new_mapper(Rom::from_bytes(&data).unwrap());
+        Vram::new(Rc::new(RefCell::new(mapper)))
+    }
+
+    // physical nametable behind $2000, $2400, $2800 and $2C00
+    fn arrangement(vram: &Vram) -> Vec<usize> {
+        (0..4).map(|table| vram.calclate_nametable_addr(0x2000 + table * 0x0400).0).collect()
+    }
+
+    #[test]
+    fn header_mirroring() {
+        assert_eq!(arrangement(&nrom_vram(0x00)), vec![0, 0, 1, 1]); // horizontal
+        assert_eq!(arrangement(&nrom_vram(0x01)), vec![0, 1, 0, 1]); // vertical
+        assert_eq!(arrangement(&nrom_vram(0x08)), vec![0, 1, 2, 3]); // four-screen
+    }
+
+    #[test]
+    fn mirrored_nametables_share_memory() {
+        let mut vram = nrom_vram(0x01);
+        vram.write(0x2005, 0x12);
+        assert_eq!(vram.read_internal(0x2805), 0x12);
+        assert_eq!(vram.read_internal(0x3005), 0x12, "$3000-$3EFF mirrors $2000-$2EFF");
+        assert_eq!(vram.read_internal(0x2405), 0x00);
+
+        let mut vram = nrom_vram(0x08);
+        for table in 0..4 {
+            vram.write(0x2000 + table * 0x0400, table as u8 + 1);
+        }
+        for table in 0..4 {
+            assert_eq!(vram.read_internal(0x2000 + table * 0x0400), table as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn single_screen_mirroring() {
+        let mapper = mapper::new_mapper(mapper::test_rom(7, 0x8000, 0));
+        let mut vram = Vram::new(Rc::new(RefCell::new(mapper)));
+        assert_eq!(arrangement(&vram), vec![0, 0, 0, 0]);
+        vram.write(0x2C00, 0x34);
+        assert_eq!(vram.read_internal(0x2000), 0x34);
+
+        vram.mapper.borrow_mut().write_prg(0x8000, 0x10);
+        assert_eq!(arrangement(&vram), vec![1, 1, 1, 1]);
+        assert_eq!(vram.read_internal(0x2000), 0x00);
+    }
+
+    #[test]
+    fn mmc1_switches_mirroring_at_runtime() {
+        let mapper = mapper::new_mapper(mapper::test_rom(1, 0x8000, 0x2000));
+        let vram = Vram::new(Rc::new(RefCell::new(mapper)));
+        let set_control = |value: u8| {
+            for i in 0..5 {
+                vram.mapper.borrow_mut().write_prg(0x8000, (value >> i) & 0x01);
+            }
+        };
+
+        set_control(0x0C);
+        assert_eq!(arrangement(&vram), vec![0, 0, 0, 0]);
+        set_control(0x0D);
+        assert_eq!(arrangement(&vram), vec![1, 1, 1, 1]);
+        set_control(0x0E);
+        assert_eq!(arrangement(&vram), vec![0, 1, 0, 1]); // vertical
+        set_control(0x0F);
+        assert_eq!(arrangement(&vram), vec![0, 0, 1, 1]); // horizontal
+    }
+}
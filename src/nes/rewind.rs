@@ -0,0 +1,219 @@
+// rewind buffer of periodic save states.
+//
+// the newest snapshot is kept as is, older ones are stored as the difference to the next newer one
+// (XOR, zero runs compressed), so dropping the oldest one never needs to rebuild others.
+use std::cmp;
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    depth: usize,    // max snapshots
+    interval: u32,   // frames between snapshots
+    frame_count: u32,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>, // oldest first
+}
+
+// latest XOR data gives the previous snapshot
+struct Delta {
+    size: usize,   // length of the XOR'ed buffers(longer one of the two)
+    length: usize, // length of the previous snapshot
+    data: Vec<u8>, // runs of (zero count, literal count, literals), counts are LEB128
+}
+
+impl Rewind {
+    pub fn new(depth: usize, interval: u32) -> Self {
+        Rewind {
+            depth: cmp::max(depth, 1),
+            interval: cmp::max(interval, 1),
+            frame_count: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // call every frame, keeps one snapshot per interval
+    pub fn push(&mut self, state: &[u8]) {
+        self.frame_count += 1;
+        if self.frame_count < self.interval && self.latest.is_some() {
+            return;
+        }
+        self.frame_count = 0;
+
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(Delta::new(state, &latest));
+        }
+        self.latest = Some(state.to_vec());
+        while self.len() > self.depth {
+            self.deltas.pop_front();
+        }
+    }
+
+    // newest snapshot, then the one before it on next call. None when exhausted
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(delta.apply(&latest));
+        }
+        self.frame_count = 0;
+        Some(latest)
+    }
+
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.frame_count = 0;
+    }
+
+    // bytes held by snapshots
+    pub fn memory_usage(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, |latest| latest.len());
+        self.deltas.iter().fold(latest, |sum, delta| sum + delta.data.len())
+    }
+}
+
+impl Delta {
+    fn new(current: &[u8], previous: &[u8]) -> Self {
+        let size = cmp::max(current.len(), previous.len());
+        let byte = |index: usize| {
+            let a = current.get(index).cloned().unwrap_or(0);
+            let b = previous.get(index).cloned().unwrap_or(0);
+            a ^ b
+        };
+
+        let mut data = vec![];
+        let mut index = 0;
+        while index < size {
+            let start = index;
+            while index < size && byte(index) == 0 {
+                index += 1;
+            }
+            let zeros = index - start;
+            let start = index;
+            while index < size && byte(index) != 0 {
+                index += 1;
+            }
+            write_count(&mut data, zeros);
+            write_count(&mut data, index - start);
+            data.extend((start..index).map(&byte));
+        }
+        Delta {
+            size: size,
+            length: previous.len(),
+            data: data,
+        }
+    }
+
+    fn apply(&self, current: &[u8]) -> Vec<u8> {
+        let mut result = current.to_vec();
+        result.resize(self.size, 0);
+        let mut data = self.data.iter().cloned();
+        let mut index = 0;
+        while index < self.size {
+            index += read_count(&mut data);
+            let literals = read_count(&mut data);
+            for (byte, value) in result[index..(index + literals)].iter_mut().zip(&mut data) {
+                *byte ^= value;
+            }
+            index += literals;
+        }
+        result.truncate(self.length);
+        result
+    }
+}
+
+fn write_count(data: &mut Vec<u8>, mut count: usize) {
+    while count >= 0x80 {
+        data.push((count & 0x7F) as u8 | 0x80);
+        count >>= 7;
+    }
+    data.push(count as u8);
+}
+
+fn read_count<I: Iterator<Item = u8>>(data: &mut I) -> usize {
+    let mut count = 0;
+    let mut shift = 0;
+    for byte in data {
+        count |= ((byte & 0x7F) as usize) << shift;
+        if (byte & 0x80) == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(frame: u8) -> Vec<u8> {
+        let mut state = vec![0x55u8; 0x1000];
+        state[0x10] = frame;
+        state[0x800] = frame.wrapping_mul(3);
+        state
+    }
+
+    #[test]
+    fn pop_returns_snapshots_newest_first() {
+        let mut rewind = Rewind::new(3, 1);
+        for frame in 0..5 {
+            rewind.push(&snapshot(frame));
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(snapshot(4)));
+        assert_eq!(rewind.pop(), Some(snapshot(3)));
+        assert_eq!(rewind.pop(), Some(snapshot(2)));
+        assert_eq!(rewind.pop(), None);
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn interval_skips_frames() {
+        let mut rewind = Rewind::new(10, 4);
+        for frame in 0..9 {
+            rewind.push(&snapshot(frame));
+        }
+        // frames 0, 4, 8
+        assert_eq!(rewind.pop(), Some(snapshot(8)));
+        assert_eq!(rewind.pop(), Some(snapshot(4)));
+        assert_eq!(rewind.pop(), Some(snapshot(0)));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn deltas_are_small_and_handle_length_change() {
+        let mut rewind = Rewind::new(10, 1);
+        let mut longer = snapshot(1);
+        longer.extend_from_slice(&[1, 2, 3]);
+        rewind.push(&snapshot(0));
+        rewind.push(&longer);
+        rewind.push(&snapshot(2));
+        assert!(rewind.memory_usage() < 0x1000 + 64);
+
+        assert_eq!(rewind.pop(), Some(snapshot(2)));
+        assert_eq!(rewind.pop(), Some(longer));
+        assert_eq!(rewind.pop(), Some(snapshot(0)));
+    }
+
+    #[test]
+    fn long_runs_round_trip() {
+        let previous: Vec<u8> = (0..0x10000).map(|i| (i / 7) as u8).collect();
+        let mut current = previous.clone();
+        for i in (0..current.len()).step_by(300) {
+            current[i] ^= 0xFF;
+        }
+        let delta = Delta::new(&current, &previous);
+        assert_eq!(delta.apply(&current), previous);
+    }
+}
@@ -1,81 +1,323 @@
 extern crate bytes;
 
-use std;
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use self::bytes::{BufMut, Bytes, BytesMut};
-use std::mem;
-use std::slice;
+use std::path::Path;
+use self::bytes::{Bytes, BytesMut};
+use nes::mapper::{self, Mirroring};
 
+const HEADER_SIZE: usize = 16;
+
+// raw 16 bytes of iNES/NES 2.0 header
 #[derive(Clone)]
 struct RomHeader {
-    magic_number: [u8; 4],
-    prg_page_count: u8,
-    chr_page_count: u8,
-    flags6: u8,
-    flags7: u8,
-    flags: [u8; 2],
-    zero: [u8; 6],
+    bytes: [u8; HEADER_SIZE],
 }
 
 impl RomHeader {
+    fn new(bytes: [u8; HEADER_SIZE]) -> Self {
+        RomHeader { bytes: bytes }
+    }
+
     fn validate_magic_number(&self) -> bool {
-        self.magic_number[0] == 0x4e && // 'N'
-        self.magic_number[1] == 0x45 && // 'E'
-        self.magic_number[2] == 0x53 && // 'S'
-        self.magic_number[3] == 0x1a // EOF(DOS)
+        self.bytes[0] == 0x4e && // 'N'
+        self.bytes[1] == 0x45 && // 'E'
+        self.bytes[2] == 0x53 && // 'S'
+        self.bytes[3] == 0x1a // EOF(DOS)
+    }
+
+    fn flags6(&self) -> u8 {
+        self.bytes[6]
+    }
+
+    fn flags7(&self) -> u8 {
+        self.bytes[7]
     }
 
-    fn mapper_no(&self) -> u16 {
-        let lower = (self.flags6 & 0xF0) as u16;
-        let upper = (self.flags7 & 0xF0) as u16;
-        lower | upper << 4
+    // flags7 bit 2-3 == 0b10
+    fn is_nes2(&self) -> bool {
+        (self.flags7() & 0x0C) == 0x08
     }
 
-    fn is_horizontal(&self) -> bool {
-        (self.flags6 & 0x01) != 0
+    // some old dumpers wrote their name to byte 7-15, then flags7 is garbage
+    fn has_dirty_tail(&self) -> bool {
+        self.bytes[12..].iter().any(|byte| *byte != 0)
     }
 
-    fn has_trainer(&self) -> bool {
-        (self.flags6 & 0x04) != 0
+    // NES 2.0 size of PRG/CHR ROM: LSB with MSB nibble, or exponent-multiplier notation.
+    // exponent goes up to 63, None when the size doesn't fit in usize
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            1usize.checked_shl(exponent).and_then(|size| size.checked_mul(multiplier))
+        } else {
+            Some(((msb as usize) << 8 | lsb as usize) * unit)
+        }
+    }
+
+    // NES 2.0 RAM size: 64 << shift bytes, 0 is none
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultipleRegion,
+    Dendy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8), // NES 2.0 byte 13
+}
+
+// cartridge description decoded from the header(NES 2.0, or iNES with defaults)
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub is_nes2: bool,
+    pub mapper_no: u16,
+    pub submapper_no: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,   // volatile
+    pub prg_nvram_size: usize, // battery-backed
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+}
+
+impl RomInfo {
+    fn from_header(header: &RomHeader) -> Result<Self, RomError> {
+        let bytes = &header.bytes;
+        let flags6 = header.flags6();
+        let mirroring = if (flags6 & 0x08) != 0 {
+            Mirroring::FourScreen
+        } else if (flags6 & 0x01) != 0 {
+            // flags6 bit 0: 0 = horizontal mirroring(vertical arrangement), 1 = vertical mirroring
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let has_battery = (flags6 & 0x02) != 0;
+        let has_trainer = (flags6 & 0x04) != 0;
+
+        if header.is_nes2() {
+            let console_type = match header.flags7() & 0x03 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem,
+                2 => ConsoleType::Playchoice10,
+                _ => ConsoleType::Extended(bytes[13] & 0x0F),
+            };
+            let timing = match bytes[12] & 0x03 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultipleRegion,
+                _ => Timing::Dendy,
+            };
+            let prg_rom_size = RomHeader::rom_size(bytes[4], bytes[9] & 0x0F, PRG_BLOCK_SIZE);
+            let chr_rom_size = RomHeader::rom_size(bytes[5], bytes[9] >> 4, CHR_BLOCK_SIZE);
+            Ok(RomInfo {
+                is_nes2: true,
+                mapper_no: (flags6 >> 4) as u16
+                    | (header.flags7() & 0xF0) as u16
+                    | ((bytes[8] & 0x0F) as u16) << 8,
+                submapper_no: bytes[8] >> 4,
+                prg_rom_size: prg_rom_size.ok_or(RomError::UnsupportedRomSize)?,
+                chr_rom_size: chr_rom_size.ok_or(RomError::UnsupportedRomSize)?,
+                prg_ram_size: RomHeader::ram_size(bytes[10] & 0x0F),
+                prg_nvram_size: RomHeader::ram_size(bytes[10] >> 4),
+                chr_ram_size: RomHeader::ram_size(bytes[11] & 0x0F),
+                chr_nvram_size: RomHeader::ram_size(bytes[11] >> 4),
+                mirroring: mirroring,
+                has_battery: has_battery,
+                has_trainer: has_trainer,
+                timing: timing,
+                console_type: console_type,
+            })
+        } else {
+            let flags7 = if header.has_dirty_tail() { 0 } else { header.flags7() };
+            // flags8: PRG RAM size in 8KB units, 0 infers 8KB for compatibility
+            let prg_ram_size = cmp::max(bytes[8] as usize, 1) * PRG_RAM_BLOCK_SIZE;
+            let chr_rom_size = bytes[5] as usize * CHR_BLOCK_SIZE;
+            Ok(RomInfo {
+                is_nes2: false,
+                mapper_no: (flags6 >> 4) as u16 | (flags7 & 0xF0) as u16,
+                submapper_no: 0,
+                prg_rom_size: bytes[4] as usize * PRG_BLOCK_SIZE,
+                chr_rom_size: chr_rom_size,
+                prg_ram_size: if has_battery { 0 } else { prg_ram_size },
+                prg_nvram_size: if has_battery { prg_ram_size } else { 0 },
+                chr_ram_size: if chr_rom_size == 0 { CHR_BLOCK_SIZE } else { 0 },
+                chr_nvram_size: 0,
+                mirroring: mirroring,
+                has_battery: has_battery,
+                has_trainer: has_trainer,
+                timing: if (bytes[9] & 0x01) != 0 { Timing::Pal } else { Timing::Ntsc },
+                console_type: match flags7 & 0x03 {
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Nes,
+                },
+            })
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Rom {
     header: RomHeader,
+    info: RomInfo,
+    trainer: Option<Bytes>, // 512 bytes, mapped to $7000-$71FF
     prg: Bytes,
     chr: Bytes,
 }
 
 const PRG_BLOCK_SIZE: usize = 16 * 1024;
 const CHR_BLOCK_SIZE: usize = 8 * 1024;
+const PRG_RAM_BLOCK_SIZE: usize = 8 * 1024;
+const TRAINER_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    TruncatedHeader,
+    BadMagicNumber,
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    UnsupportedMapper(u16),
+    OversizedImage { expected: usize, actual: usize },
+    UnsupportedRomSize, // NES 2.0 exponent-multiplier size too large for this machine
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref err) => write!(f, "cant read ROM: {}", err),
+            RomError::TruncatedHeader => write!(f, "ROM is shorter than header"),
+            RomError::BadMagicNumber => write!(f, "not an iNES ROM(bad magic number)"),
+            RomError::TruncatedTrainer => write!(f, "trainer is truncated"),
+            RomError::TruncatedPrg { expected, actual } => {
+                write!(f, "PRG ROM is truncated: expected {} bytes, got {}", expected, actual)
+            }
+            RomError::TruncatedChr { expected, actual } => {
+                write!(f, "CHR ROM is truncated: expected {} bytes, got {}", expected, actual)
+            }
+            RomError::UnsupportedMapper(no) => write!(f, "unsupported mapper:{}", no),
+            RomError::OversizedImage { expected, actual } => {
+                write!(f, "ROM image is oversized: expected {} bytes, got {}", expected, actual)
+            }
+            RomError::UnsupportedRomSize => write!(f, "PRG/CHR ROM size in header is too large"),
+        }
+    }
+}
+
+impl error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
 
 impl Rom {
-    pub fn load<S: Into<String>>(filename: S) -> Result<(Box<Rom>), std::io::Error> {
-        let mut file = File::open(filename.into())?;
-        let header = Rom::load_header(&mut file)?;
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Box<Rom>, RomError> {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Rom::from_bytes(&data)
+    }
+
+    // header, trainer(optional 512 bytes), PRG ROM, CHR ROM
+    pub fn from_bytes(data: &[u8]) -> Result<Box<Rom>, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes.copy_from_slice(&data[..HEADER_SIZE]);
+        let header = RomHeader::new(bytes);
+        if !header.validate_magic_number() {
+            return Err(RomError::BadMagicNumber);
+        }
+        let info = RomInfo::from_header(&header)?;
+        if !mapper::is_supported(info.mapper_no) {
+            return Err(RomError::UnsupportedMapper(info.mapper_no));
+        }
 
-        let mut prg = BytesMut::with_capacity(PRG_BLOCK_SIZE * header.prg_page_count as usize);
-        Rom::read_file(&mut file, &mut prg, 16 * header.prg_page_count as usize)?;
+        let mut rest = &data[HEADER_SIZE..];
+        let trainer = if info.has_trainer {
+            if rest.len() < TRAINER_SIZE {
+                return Err(RomError::TruncatedTrainer);
+            }
+            let (trainer, tail) = rest.split_at(TRAINER_SIZE);
+            rest = tail;
+            Some(Bytes::from(trainer))
+        } else {
+            None
+        };
+
+        if rest.len() < info.prg_rom_size {
+            return Err(RomError::TruncatedPrg {
+                expected: info.prg_rom_size,
+                actual: rest.len(),
+            });
+        }
+        let (prg, rest) = rest.split_at(info.prg_rom_size);
+
+        if rest.len() < info.chr_rom_size {
+            return Err(RomError::TruncatedChr {
+                expected: info.chr_rom_size,
+                actual: rest.len(),
+            });
+        }
+        let (chr, rest) = rest.split_at(info.chr_rom_size);
 
-        let mut chr = BytesMut::with_capacity(CHR_BLOCK_SIZE * header.chr_page_count as usize);
-        Rom::read_file(&mut file, &mut chr, 8 * header.chr_page_count as usize)?;
+        if !rest.is_empty() {
+            return Err(RomError::OversizedImage {
+                expected: data.len() - rest.len(),
+                actual: data.len(),
+            });
+        }
 
         let rom = Rom {
             header: header,
-            prg: prg.freeze(),
-            chr: chr.freeze(),
+            info: info,
+            trainer: trainer,
+            prg: Bytes::from(prg),
+            chr: Bytes::from(chr),
         };
         Ok(Box::new(rom))
     }
 
     pub fn empty() -> Box<Rom> {
-        let header: RomHeader = unsafe { mem::zeroed() };
+        let header = RomHeader::new([0u8; HEADER_SIZE]);
+        // iNES header without sizes can't fail
+        let info = RomInfo::from_header(&header).unwrap();
         let prg = BytesMut::with_capacity(0);
         let chr = BytesMut::with_capacity(0);
         let rom = Rom {
             header: header,
+            info: info,
+            trainer: None,
             prg: prg.freeze(),
             chr: chr.freeze(),
         };
@@ -83,7 +325,7 @@ impl Rom {
     }
 
     pub fn print(&self) {
-        let magic_number = self.header.magic_number;
+        let magic_number = &self.header.bytes[0..4];
         info!("=======ROM Information=======");
         info!(
             "magic_number:[{}{}{}{}]",
@@ -96,18 +338,13 @@ impl Rom {
             "validate_magic_number:{}",
             self.header.validate_magic_number()
         );
-        info!("prg_page_count:{}", self.header.prg_page_count);
-        info!("chr_page_count:{}", self.header.chr_page_count);
-        info!("mapper_no:{}", self.header.mapper_no());
-        info!("flags6:{}", self.header.flags6);
-        info!("is_horizontal:{}", self.is_horizontal());
-        info!("has_trainer:{}", self.has_trainer());
+        info!("{:?}", self.info);
         info!("PRG Len:{}", self.prg.len());
         info!("CHR Len:{}", self.chr.len());
     }
 
     pub fn read_prg(&self, addr: u16) -> u8 {
-        if self.header.prg_page_count == 1 {
+        if self.prg.len() == PRG_BLOCK_SIZE {
             let x = addr & 0x3FFF;
             self.prg[x as usize]
         } else {
@@ -115,55 +352,161 @@ impl Rom {
         }
     }
 
+    pub fn info(&self) -> &RomInfo {
+        &self.info
+    }
+
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_ref().map(|trainer| &trainer[..])
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
     pub fn chr(&self) -> &[u8] {
         &self.chr
     }
 
+    pub fn mapper_no(&self) -> u16 {
+        self.info.mapper_no
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.info.mirroring
+    }
+
     pub fn initial_pc(&self) -> u16 {
         let mut pc = 0x8000;
-        let header = &self.header;
-        if header.prg_page_count == 2 {
+        if self.prg.len() == PRG_BLOCK_SIZE * 2 {
             let head = &self.prg[0..PRG_BLOCK_SIZE];
             let tail = &self.prg[PRG_BLOCK_SIZE..(PRG_BLOCK_SIZE * 2)];
-            if header.mapper_no() == 0 && head == tail {
+            if self.info.mapper_no == 0 && head == tail {
                 pc = 0xc000
             }
-        } else if header.prg_page_count == 1 {
+        } else if self.prg.len() == PRG_BLOCK_SIZE {
             pc = 0xc000
         }
 
         pc
     }
 
-    pub fn is_horizontal(&self) -> bool {
-        self.header.is_horizontal()
+    pub fn has_trainer(&self) -> bool {
+        self.info.has_trainer
     }
 
-    pub fn has_trainer(&self) -> bool {
-        self.header.has_trainer()
+    pub fn has_battery(&self) -> bool {
+        self.info.has_battery
     }
 
-    fn load_header(file: &mut File) -> Result<(RomHeader), std::io::Error> {
-        let mut header: RomHeader = unsafe { mem::zeroed() };
-        let header_size = mem::size_of::<RomHeader>();
-        unsafe {
-            let dst_ptr = &mut header as *mut RomHeader as *mut u8;
-            let mut slice = slice::from_raw_parts_mut(dst_ptr, header_size);
-            file.read_exact(slice)?;
+    // volatile and battery-backed PRG RAM share $6000-$7FFF
+    pub fn prg_ram_size(&self) -> usize {
+        self.info.prg_ram_size + self.info.prg_nvram_size
+    }
+
+    // CHR RAM is used only when there is no CHR ROM
+    pub fn chr_ram_size(&self) -> usize {
+        self.info.chr_ram_size + self.info.chr_nvram_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(bytes: [u8; HEADER_SIZE]) -> RomInfo {
+        RomInfo::from_header(&RomHeader::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn ines_header_uses_defaults() {
+        let info = info([0x4e, 0x45, 0x53, 0x1a, 2, 0, 0x13, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!info.is_nes2);
+        assert_eq!(info.mapper_no, 0x41);
+        assert_eq!(info.prg_rom_size, 32 * 1024);
+        assert_eq!(info.chr_ram_size, 8 * 1024);
+        assert_eq!(info.prg_nvram_size, 8 * 1024);
+        assert_eq!(info.mirroring, Mirroring::Vertical);
+        assert!(info.has_battery);
+    }
+
+    #[test]
+    fn ines_header_with_dirty_tail_ignores_flags7() {
+        let info = info([0x4e, 0x45, 0x53, 0x1a, 1, 1, 0x10, 0x44, 0, 0, 0, 0, 0x44, 0x69, 0x73, 0x6b]);
+        assert_eq!(info.mapper_no, 1);
+    }
+
+    #[test]
+    fn nes2_header_is_decoded() {
+        let info = info([0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x48, 0x19, 0x31, 0x10, 0x70, 0x07, 0x01, 0, 0, 0]);
+        assert!(info.is_nes2);
+        assert_eq!(info.mapper_no, 0x114);
+        assert_eq!(info.submapper_no, 3);
+        assert_eq!(info.prg_rom_size, 2 * 16 * 1024);
+        assert_eq!(info.chr_rom_size, 0x101 * 8 * 1024);
+        assert_eq!(info.prg_ram_size, 0);
+        assert_eq!(info.prg_nvram_size, 8 * 1024);
+        assert_eq!(info.chr_ram_size, 8 * 1024);
+        assert_eq!(info.mirroring, Mirroring::FourScreen);
+        assert_eq!(info.timing, Timing::Pal);
+        assert_eq!(info.console_type, ConsoleType::VsSystem);
+    }
+
+    #[test]
+    fn nes2_exponent_size() {
+        // 2^5 * (1 * 2 + 1)
+        assert_eq!(RomHeader::rom_size(0x15, 0x0F, PRG_BLOCK_SIZE), Some(96));
+        // 2^63 * 7
+        assert_eq!(RomHeader::rom_size(0xFF, 0x0F, PRG_BLOCK_SIZE), None);
+    }
+
+    #[test]
+    fn nes2_header_with_overflowing_size_is_rejected() {
+        let header = [0x4e, 0x45, 0x53, 0x1a, 0xFF, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0];
+        match Rom::from_bytes(&header) {
+            Err(RomError::UnsupportedRomSize) => {}
+            _ => panic!("expected UnsupportedRomSize"),
         }
-        Ok(header)
     }
 
-    fn read_file(
-        file: &mut File,
-        bytes: &mut BytesMut,
-        read_kbyte: usize,
-    ) -> Result<(), std::io::Error> {
-        let mut buf = [0 as u8; 1024];
-        for _ in 0..read_kbyte {
-            file.read_exact(&mut buf)?;
-            bytes.put_slice(&mut buf);
+    fn image(header: [u8; HEADER_SIZE], body_size: usize) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.extend((0..body_size).map(|i| i as u8));
+        data
+    }
+
+    #[test]
+    fn load_from_bytes_with_trainer() {
+        let header = [0x4e, 0x45, 0x53, 0x1a, 1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let rom = Rom::from_bytes(&image(header, TRAINER_SIZE + PRG_BLOCK_SIZE + CHR_BLOCK_SIZE)).unwrap();
+        assert_eq!(rom.trainer().unwrap().len(), TRAINER_SIZE);
+        // PRG starts after trainer
+        assert_eq!(rom.prg()[0], (TRAINER_SIZE % 256) as u8);
+        assert_eq!(rom.chr().len(), CHR_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn load_errors() {
+        let header = [0x4e, 0x45, 0x53, 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        match Rom::from_bytes(&image(header, PRG_BLOCK_SIZE)) {
+            Err(RomError::TruncatedChr { expected, actual: 0 }) => assert_eq!(expected, CHR_BLOCK_SIZE),
+            _ => panic!("expected TruncatedChr"),
+        }
+        match Rom::from_bytes(&image(header, PRG_BLOCK_SIZE + CHR_BLOCK_SIZE + 1)) {
+            Err(RomError::OversizedImage { .. }) => {}
+            _ => panic!("expected OversizedImage"),
+        }
+        let mut bad = header;
+        bad[3] = 0;
+        match Rom::from_bytes(&image(bad, PRG_BLOCK_SIZE + CHR_BLOCK_SIZE)) {
+            Err(RomError::BadMagicNumber) => {}
+            _ => panic!("expected BadMagicNumber"),
+        }
+        let mut unsupported = header;
+        unsupported[6] = 0xF0;
+        match Rom::from_bytes(&image(unsupported, PRG_BLOCK_SIZE + CHR_BLOCK_SIZE)) {
+            Err(RomError::UnsupportedMapper(15)) => {}
+            _ => panic!("expected UnsupportedMapper"),
         }
-        Ok(())
     }
 }
@@ -0,0 +1,299 @@
+// save state serialization
+//
+// all integers are little endian.
+//
+//   offset size
+//   0      4    magic number "RNES"
+//   4      4    format version(u32), STATE_VERSION
+//   8      8    checksum of PRG ROM + CHR ROM(u64, FNV-1a), state is bound to the ROM
+//   16     -    sections
+//
+// each section is a 4 byte ASCII tag, payload length(u32) and payload.
+// sections appear in this order:
+//   "CPU " registers, cycle counter
+//   "PPU " registers, loopy v/t/x/w, OAM, secondary OAM, rendering pipeline, line/dot counters,
+//          nametables, palette, pending OAM DMA
+//   "APU " channels, frame counter, cycle counter(audio output buffers are not saved)
+//   "MBC " internal RAM, PRG RAM
+//   "MAPR" mapper registers, CHR RAM
+//   "JOYP" joypad shift register
+//
+// byte arrays are stored as length(u32) + bytes, bool as one byte(0 or 1).
+// bump STATE_VERSION whenever the layout of any section changes.
+use std::error;
+use std::fmt;
+
+pub const STATE_VERSION: u32 = 2;
+const MAGIC_NUMBER: &[u8; 4] = b"RNES";
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagicNumber,
+    UnsupportedVersion(u32),
+    RomMismatch,
+    UnexpectedSection { expected: String, actual: String },
+    Truncated,
+    SizeMismatch { expected: usize, actual: usize },
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagicNumber => write!(f, "not a save state(bad magic number)"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version:{}, expected {}", version, STATE_VERSION)
+            }
+            StateError::RomMismatch => write!(f, "save state was made with another ROM"),
+            StateError::UnexpectedSection { ref expected, ref actual } => {
+                write!(f, "unexpected section: expected {}, got {}", expected, actual)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::SizeMismatch { expected, actual } => {
+                write!(f, "memory size mismatch: expected {} bytes, got {}", expected, actual)
+            }
+            StateError::InvalidValue(name) => write!(f, "invalid value of {}", name),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+// FNV-1a
+pub fn checksum(chunks: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for chunk in chunks {
+        for byte in chunk.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    // starts with the header
+    pub fn new(rom_checksum: u64) -> Self {
+        let mut writer = StateWriter { data: vec![] };
+        writer.data.extend_from_slice(MAGIC_NUMBER);
+        writer.write_u32(STATE_VERSION);
+        writer.write_u64(rom_checksum);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], write: F) {
+        self.data.extend_from_slice(tag);
+        let length_index = self.data.len();
+        self.write_u32(0);
+        write(self);
+        let length = (self.data.len() - length_index - 4) as u32;
+        self.data[length_index..(length_index + 4)].copy_from_slice(&u32_to_bytes(length));
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.push(value as u8);
+        self.data.push((value >> 8) as u8);
+    }
+
+    pub fn write_i16(&mut self, value: i16) {
+        self.write_u16(value as u16);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&u32_to_bytes(value));
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_u32(value as u32);
+        self.write_u32((value >> 32) as u32);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+fn u32_to_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // checks the header, returns reader positioned at the first section
+    pub fn new(data: &'a [u8], rom_checksum: u64) -> Result<Self, StateError> {
+        let mut reader = StateReader {
+            data: data,
+            position: 0,
+        };
+        if reader.take(4).map_err(|_| StateError::BadMagicNumber)? != MAGIC_NUMBER {
+            return Err(StateError::BadMagicNumber);
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.read_u64()? != rom_checksum {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(reader)
+    }
+
+    // reader limited to the payload of next section
+    pub fn section(&mut self, tag: &[u8; 4]) -> Result<StateReader<'a>, StateError> {
+        let actual = self.take(4)?;
+        if actual != tag {
+            return Err(StateError::UnexpectedSection {
+                expected: String::from_utf8_lossy(tag).into_owned(),
+                actual: String::from_utf8_lossy(actual).into_owned(),
+            });
+        }
+        let length = self.read_u32()? as usize;
+        Ok(StateReader {
+            data: self.take(length)?,
+            position: 0,
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < length {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.position..(self.position + length)];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, StateError> {
+        Ok(self.read_u16()? as i16)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let low = self.read_u32()? as u64;
+        let high = self.read_u32()? as u64;
+        Ok(low | high << 32)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    // fixed size memory(RAM, OAM...), size is checked
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::SizeMismatch {
+                expected: buffer.len(),
+                actual: bytes.len(),
+            });
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// bare section payload, for testing load_state of a single component
+#[cfg(test)]
+impl StateWriter {
+    pub fn payload() -> Self {
+        StateWriter { data: vec![] }
+    }
+}
+
+#[cfg(test)]
+impl<'a> StateReader<'a> {
+    pub fn payload(data: &'a [u8]) -> Self {
+        StateReader {
+            data: data,
+            position: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new(42);
+        writer.section(b"TEST", |state| {
+            state.write_u8(0x12);
+            state.write_bool(true);
+            state.write_u16(0x3456);
+            state.write_i16(-1);
+            state.write_u64(0x0123456789ABCDEF);
+            state.write_bytes(&[1, 2, 3]);
+        });
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data, 42).unwrap();
+        let mut section = reader.section(b"TEST").unwrap();
+        assert_eq!(section.read_u8(), Ok(0x12));
+        assert_eq!(section.read_bool(), Ok(true));
+        assert_eq!(section.read_u16(), Ok(0x3456));
+        assert_eq!(section.read_i16(), Ok(-1));
+        assert_eq!(section.read_u64(), Ok(0x0123456789ABCDEF));
+        let mut buffer = [0u8; 2];
+        assert_eq!(
+            section.read_bytes_into(&mut buffer),
+            Err(StateError::SizeMismatch { expected: 2, actual: 3 })
+        );
+        assert_eq!(section.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let data = StateWriter::new(42).into_bytes();
+        assert!(StateReader::new(&data, 42).is_ok());
+        assert_eq!(StateReader::new(&data, 43).err(), Some(StateError::RomMismatch));
+        assert_eq!(StateReader::new(b"NES\x1a", 42).err(), Some(StateError::BadMagicNumber));
+
+        let mut data = data.clone();
+        data[4] = 99;
+        assert_eq!(StateReader::new(&data, 42).err(), Some(StateError::UnsupportedVersion(99)));
+
+        let data = StateWriter::new(42).into_bytes();
+        let mut reader = StateReader::new(&data, 42).unwrap();
+        assert_eq!(reader.section(b"CPU ").err(), Some(StateError::Truncated));
+    }
+}
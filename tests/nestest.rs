@@ -0,0 +1,65 @@
+// CPU conformance against nestest golden log.
+//
+// nestest.nes and nestest.log(the version with "PPU:line,dot CYC:n" columns) are not bundled,
+// scripts/nestest.sh downloads them to tests/nestest/ and runs `cargo test -- --ignored`.
+extern crate rust_nes;
+
+use rust_nes::Nes;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// nestest runs without display from here
+const START_PC: u16 = 0xC000;
+// give up when no trace line comes for this many ticks
+const MAX_TICKS_PER_LINE: usize = 100;
+
+#[test]
+#[ignore = "needs tests/nestest/nestest.nes and nestest.log"]
+fn nestest_matches_golden_log() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("nestest");
+    let rom_path = dir.join("nestest.nes");
+    let log_path = dir.join("nestest.log");
+    assert!(
+        rom_path.exists() && log_path.exists(),
+        "nestest.nes and nestest.log are not found in {}",
+        dir.display()
+    );
+
+    let mut log = String::new();
+    File::open(&log_path)
+        .and_then(|mut file| file.read_to_string(&mut log))
+        .unwrap();
+    let expected: Vec<&str> = log.lines().map(|line| line.trim_end()).collect();
+
+    let mut nes = Nes::new();
+    nes.load_rom(&rom_path).unwrap();
+    nes.set_pc(START_PC);
+    nes.set_trace(true);
+
+    let mut actual = vec![];
+    let mut ticks = 0;
+    while actual.len() < expected.len() {
+        nes.tick();
+        let lines = actual.len();
+        nes.take_trace(&mut actual);
+        ticks = if actual.len() == lines { ticks + 1 } else { 0 };
+        assert!(ticks < MAX_TICKS_PER_LINE, "CPU stopped after line {}", lines);
+    }
+
+    for (no, (expected_line, actual_line)) in expected.iter().zip(actual.iter()).enumerate() {
+        if expected_line != actual_line {
+            panic!(
+                "first divergence at line {}:\n  previous: {}\n  expected: {}\n  actual:   {}",
+                no + 1,
+                if no > 0 { expected[no - 1] } else { "" },
+                expected_line,
+                actual_line
+            );
+        }
+    }
+
+    // nestest stores the number of the first failed test at $02(official) and $03(unofficial)
+    assert_eq!(nes.peek(0x0002), Some(0x00), "official opcode test failed");
+    assert_eq!(nes.peek(0x0003), Some(0x00), "unofficial opcode test failed");
+}